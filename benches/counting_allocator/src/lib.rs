@@ -0,0 +1,68 @@
+//! The system allocator with a count of every allocation made, so benchmarks can report
+//! how many allocations an operation costs. Linking this crate makes it the allocator for
+//! the whole binary.
+#![feature(allocator, libc, no_std)]
+#![allocator]
+#![no_std]
+
+extern crate libc;
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+// malloc already aligns to this on every platform we run on
+const MIN_ALIGN: usize = 16;
+
+static ALLOCATIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Allocations and reallocations made so far, by any thread.
+pub fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::SeqCst)
+}
+
+#[no_mangle]
+pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+
+    if align <= MIN_ALIGN {
+        return unsafe { libc::malloc(size as libc::size_t) as *mut u8 };
+    }
+
+    let mut pointer = 0 as *mut libc::c_void;
+    match unsafe { libc::posix_memalign(&mut pointer, align as libc::size_t, size as libc::size_t) } {
+        0 => pointer as *mut u8,
+        _ => 0 as *mut u8
+    }
+}
+
+#[no_mangle]
+pub extern fn __rust_deallocate(pointer: *mut u8, _old_size: usize, _align: usize) {
+    unsafe { libc::free(pointer as *mut libc::c_void) }
+}
+
+#[no_mangle]
+pub extern fn __rust_reallocate(pointer: *mut u8, old_size: usize, size: usize, align: usize) -> *mut u8 {
+    if align <= MIN_ALIGN {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        return unsafe { libc::realloc(pointer as *mut libc::c_void, size as libc::size_t) as *mut u8 };
+    }
+
+    let new_pointer = __rust_allocate(size, align);
+    if !new_pointer.is_null() {
+        unsafe {
+            core::ptr::copy(pointer, new_pointer, core::cmp::min(old_size, size));
+            libc::free(pointer as *mut libc::c_void);
+        }
+    }
+
+    new_pointer
+}
+
+#[no_mangle]
+pub extern fn __rust_reallocate_inplace(_pointer: *mut u8, old_size: usize, _size: usize, _align: usize) -> usize {
+    old_size
+}
+
+#[no_mangle]
+pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
+    size
+}
@@ -0,0 +1,74 @@
+//! Compares the allocating `HttpRequestBuilder` with its zero-copy mode and with
+//! `RequestHead` on its own, on the same request head. The builder copies the method,
+//! target, version and every header name and value byte by byte before storing them,
+//! the zero-copy mode only allocates what the request keeps, and `RequestHead` only fills
+//! in offsets in a stack array.
+//!
+//! Besides the timings each benchmark prints how many allocations one parse makes,
+//! counted by `counting_allocator`, including the copy of the request into its buffer.
+//! The allocator is only built for this benchmark, so run it with `cargo bench --features bench`.
+#![feature(test)]
+extern crate bytes;
+extern crate counting_allocator;
+extern crate http;
+extern crate test;
+
+use std::io::{self, Write};
+use bytes::{Buf, ByteBuf};
+use http::{HeaderSpan, HttpRequestBuilder, HttpResult, ParserLimits, ParserMode, RequestHead};
+use test::Bencher;
+
+const REQUEST: &'static str = "GET /api/v1/users/42?fields=name,email HTTP/1.1\r\n\
+    Host: api.example.com\r\n\
+    User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:40.0) Gecko/20100101 Firefox/40.0\r\n\
+    Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n\
+    Accept-Language: en-US,en;q=0.5\r\n\
+    Accept-Encoding: gzip, deflate\r\n\
+    Cookie: session=4a2b7c9d1e3f; theme=dark\r\n\
+    Connection: keep-alive\r\n\
+    Cache-Control: max-age=0\r\n\r\n";
+
+/// Runs one parse outside the timed loop and reports the allocations it made.
+fn report_allocations<F: FnMut()>(name: &str, mut parse: F) {
+    let before = counting_allocator::allocations();
+    parse();
+    let allocations = counting_allocator::allocations() - before;
+    let _ = writeln!(io::stderr(), "{}: {} allocations per request", name, allocations);
+}
+
+fn builder_parse(zero_copy: bool) {
+    let builder = HttpRequestBuilder::new(ParserLimits::new()).zero_copy(zero_copy);
+    match builder.parse(ByteBuf::from_slice(REQUEST.as_bytes())) {
+        Ok(HttpResult::Http1Request{request, ..}) => test::black_box(request),
+        _ => panic!("Expected Http1Request")
+    };
+}
+
+fn head_parse() {
+    // The same buffer copy the builder gets, so only parsing differs
+    let buffer = ByteBuf::from_slice(REQUEST.as_bytes());
+    let mut headers = [HeaderSpan::default(); 16];
+    let head = RequestHead::parse(buffer.bytes(), &mut headers, &ParserLimits::new(), ParserMode::Lenient).unwrap().unwrap();
+    test::black_box(head.header("Host"));
+}
+
+#[bench]
+fn bench_builder_parse(b: &mut Bencher) {
+    report_allocations("builder", || builder_parse(false));
+    b.bytes = REQUEST.len() as u64;
+    b.iter(|| builder_parse(false));
+}
+
+#[bench]
+fn bench_builder_zero_copy_parse(b: &mut Bencher) {
+    report_allocations("builder, zero copy", || builder_parse(true));
+    b.bytes = REQUEST.len() as u64;
+    b.iter(|| builder_parse(true));
+}
+
+#[bench]
+fn bench_request_head_parse(b: &mut Bencher) {
+    report_allocations("request head", head_parse);
+    b.bytes = REQUEST.len() as u64;
+    b.iter(head_parse);
+}
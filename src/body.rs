@@ -0,0 +1,542 @@
+use std::cmp;
+use std::mem;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use headers::HeaderMap;
+
+struct BodyState {
+    chunks: VecDeque<Vec<u8>>,
+    buffered: usize,
+    limit: usize,
+    finished: bool,
+    aborted: bool,
+    waiting: bool,
+    trailers: HeaderMap,
+    resume: Box<Fn() + Send>
+}
+
+impl BodyState {
+    fn consumed(&mut self, length: usize) {
+        self.buffered -= length;
+
+        if self.waiting && self.buffered < self.limit {
+            self.waiting = false;
+            (self.resume)();
+        }
+    }
+}
+
+/// The body of a request whose handler chose to stream it, see `Handler::stream_body`.
+///
+/// Chunks are handed over as the connection reads them from the socket. Once `limit`
+/// bytes are waiting unread the connection stops reading until the handler catches up.
+/// `read` blocks until data arrives, so it must not be called on the event loop thread,
+/// where handlers use `try_read` instead.
+pub struct BodyStream {
+    shared: Arc<(Mutex<BodyState>, Condvar)>
+}
+
+/// The connection's end of a `BodyStream`. Dropping it before `finish` fails the reader.
+pub struct BodySender {
+    shared: Arc<(Mutex<BodyState>, Condvar)>
+}
+
+/// Creates a connected sender and stream. `resume` is called once a reader has made room
+/// again after `BodySender::is_full` returned true.
+pub fn stream(limit: usize, resume: Box<Fn() + Send>) -> (BodySender, BodyStream) {
+    let shared = Arc::new((Mutex::new(BodyState {
+        chunks: VecDeque::new(),
+        buffered: 0,
+        limit: limit,
+        finished: false,
+        aborted: false,
+        waiting: false,
+        trailers: HeaderMap::new(),
+        resume: resume
+    }), Condvar::new()));
+
+    (BodySender {shared: shared.clone()}, BodyStream {shared: shared})
+}
+
+impl BodySender {
+    pub fn push(&self, data: Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+
+        let (ref lock, ref condvar) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        state.buffered += data.len();
+        state.chunks.push_back(data);
+        condvar.notify_all();
+    }
+
+    pub fn finish(self, trailers: HeaderMap) {
+        let (ref lock, ref condvar) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        state.finished = true;
+        state.trailers = trailers;
+        condvar.notify_all();
+    }
+
+    /// Whether the reader has fallen far enough behind that the connection should stop
+    /// reading, in which case the resume callback fires once it catches up.
+    pub fn is_full(&self) -> bool {
+        let (ref lock, _) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        state.waiting = state.buffered >= state.limit;
+        state.waiting
+    }
+}
+
+impl Drop for BodySender {
+    fn drop(&mut self) {
+        let (ref lock, ref condvar) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        if !state.finished {
+            state.aborted = true;
+            condvar.notify_all();
+        }
+    }
+}
+
+impl BodyStream {
+    /// The next chunk if one has arrived, without waiting.
+    pub fn try_read(&self) -> Option<Vec<u8>> {
+        let (ref lock, _) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        let chunk = state.chunks.pop_front();
+
+        if let Some(ref chunk) = chunk {
+            state.consumed(chunk.len());
+        }
+        chunk
+    }
+
+    /// Whether the whole body has been received and read.
+    pub fn is_finished(&self) -> bool {
+        let (ref lock, _) = *self.shared;
+        let state = lock.lock().unwrap();
+        state.finished && state.chunks.is_empty()
+    }
+
+    /// Whether the connection failed before the whole body was received.
+    pub fn is_aborted(&self) -> bool {
+        let (ref lock, _) = *self.shared;
+        lock.lock().unwrap().aborted
+    }
+
+    /// Trailers sent after a chunked body, available once the body has been received.
+    pub fn trailers(&self) -> Option<HeaderMap> {
+        let (ref lock, _) = *self.shared;
+        let state = lock.lock().unwrap();
+        match state.finished {
+            true => Some(state.trailers.clone()),
+            false => None
+        }
+    }
+}
+
+impl Read for BodyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (ref lock, ref condvar) = *self.shared;
+        let mut state = lock.lock().unwrap();
+
+        loop {
+            if let Some(chunk) = state.chunks.pop_front() {
+                let length = cmp::min(buf.len(), chunk.len());
+                for (to, from) in buf.iter_mut().zip(chunk.iter()) {
+                    *to = *from;
+                }
+
+                if length < chunk.len() {
+                    state.chunks.push_front(chunk[length ..].to_vec());
+                }
+
+                state.consumed(length);
+                return Ok(length);
+            }
+
+            if state.finished {
+                return Ok(0);
+            }
+
+            if state.aborted {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Connection closed before the body was received"));
+            }
+
+            state = condvar.wait(state).unwrap();
+        }
+    }
+}
+
+/// How much a `BodySink` buffers before its response has started being written, after
+/// which the server's buffer size applies.
+const INITIAL_SINK_LIMIT: usize = 16 * 1024;
+
+struct SinkState {
+    data: Vec<u8>,
+    limit: usize,
+    finished: bool,
+    aborted: bool,
+    closed: bool,
+    trailers: HeaderMap,
+    notified: bool,
+    waiting: bool,
+    wake: Option<Box<Fn() + Send>>,
+    ready: Option<Box<Fn() + Send>>
+}
+
+impl SinkState {
+    /// Tells the connection there is something to pick up, once until it next does.
+    fn wake_connection(&mut self) {
+        if self.notified {
+            return;
+        }
+
+        if let Some(ref wake) = self.wake {
+            self.notified = true;
+            wake();
+        }
+    }
+}
+
+/// The writing end of a streamed response body, see `HttpResponse::streaming`.
+///
+/// Data goes out as fast as the client takes it. Once a buffer's worth is waiting `write`
+/// blocks and `is_ready` returns false, until the socket has taken everything buffered and
+/// the `on_ready` callback runs. The body ends with `finish`, dropping the sink before that
+/// cuts the response short and closes the connection.
+///
+/// Since `write` waits for the connection, it must never be called on the event loop's
+/// thread, which runs `Handler::handle` and the `on_ready` callback. Only the socket can
+/// make room, so it would wait forever. Use `send` and `is_ready` there instead.
+pub struct BodySink {
+    shared: Arc<(Mutex<SinkState>, Condvar)>
+}
+
+/// The connection's end of a `BodySink`.
+pub struct BodyReceiver {
+    shared: Arc<(Mutex<SinkState>, Condvar)>
+}
+
+/// Creates a connected sink and receiver, with `data` already written.
+pub fn sink(data: Vec<u8>) -> (BodySink, BodyReceiver) {
+    let shared = Arc::new((Mutex::new(SinkState {
+        data: data,
+        limit: INITIAL_SINK_LIMIT,
+        finished: false,
+        aborted: false,
+        closed: false,
+        trailers: HeaderMap::new(),
+        notified: false,
+        waiting: false,
+        wake: None,
+        ready: None
+    }), Condvar::new()));
+
+    (BodySink {shared: shared.clone()}, BodyReceiver {shared: shared})
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed before the body was sent")
+}
+
+impl BodySink {
+    /// Queues data without waiting for room, failing once the connection has gone away.
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        let (ref lock, _) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        if state.closed {
+            return Err(connection_closed());
+        }
+
+        state.data.extend(data.iter().cloned());
+        state.wake_connection();
+        Ok(())
+    }
+
+    /// Whether there is room for more data. When there isn't the `on_ready` callback runs
+    /// once there is. Also true once the connection has closed, so the next write fails.
+    pub fn is_ready(&self) -> bool {
+        let (ref lock, _) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        state.waiting = !state.closed && state.data.len() >= state.limit;
+        !state.waiting
+    }
+
+    /// Sets the callback run on the event loop when the socket can take more data after
+    /// `is_ready` returned false or a `write` had to wait, and when the connection closes.
+    pub fn on_ready<F>(&self, callback: F) where F: Fn() + Send + 'static {
+        let (ref lock, _) = *self.shared;
+        lock.lock().unwrap().ready = Some(Box::new(callback));
+    }
+
+    /// Ends the body, sending `trailers` after it if the response is chunked.
+    pub fn finish(self, trailers: HeaderMap) {
+        let (ref lock, _) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        state.finished = true;
+        state.trailers = trailers;
+        state.wake_connection();
+    }
+}
+
+impl Write for BodySink {
+    /// Blocks while a buffer's worth is waiting, so never call this on the event loop's thread.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (ref lock, ref condvar) = *self.shared;
+        let mut state = lock.lock().unwrap();
+
+        while !state.closed && state.data.len() >= state.limit {
+            state.waiting = true;
+            state = condvar.wait(state).unwrap();
+        }
+
+        if state.closed {
+            return Err(connection_closed());
+        }
+
+        state.data.extend(buf.iter().cloned());
+        state.wake_connection();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BodySink {
+    fn drop(&mut self) {
+        let (ref lock, _) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        if !state.finished {
+            state.aborted = true;
+            state.wake_connection();
+        }
+    }
+}
+
+impl BodyReceiver {
+    /// Starts handing data to the connection, which `wake` tells about anything new.
+    pub fn attach(&self, limit: usize, wake: Box<Fn() + Send>) {
+        let (ref lock, _) = *self.shared;
+        let mut state = lock.lock().unwrap();
+        state.limit = limit;
+        state.wake = Some(wake);
+        state.notified = false;
+    }
+
+    /// Whether there is data or the end of the body to pick up.
+    pub fn is_ready(&self) -> bool {
+        let (ref lock, _) = *self.shared;
+        let state = lock.lock().unwrap();
+        !state.data.is_empty() || state.finished || state.aborted
+    }
+
+    /// Takes everything written so far, along with the trailers once the body is finished.
+    /// Only called once the socket has taken what came before, so a waiting writer is woken.
+    pub fn take(&self) -> (Vec<u8>, Option<HeaderMap>) {
+        let (ref lock, ref condvar) = *self.shared;
+        let (data, trailers, ready) = {
+            let mut state = lock.lock().unwrap();
+            state.notified = false;
+
+            let data = mem::replace(&mut state.data, Vec::new());
+            let trailers = match state.finished {
+                true => Some(mem::replace(&mut state.trailers, HeaderMap::new())),
+                false => None
+            };
+
+            let ready = match mem::replace(&mut state.waiting, false) {
+                true => {
+                    condvar.notify_all();
+                    state.ready.take()
+                },
+                false => None
+            };
+
+            (data, trailers, ready)
+        };
+
+        // The callback may well write more, so it runs without the lock held
+        if let Some(ready) = ready {
+            ready();
+
+            let mut state = lock.lock().unwrap();
+            if state.ready.is_none() {
+                state.ready = Some(ready);
+            }
+        }
+
+        (data, trailers)
+    }
+
+    /// Whether the sink was dropped without finishing the body.
+    pub fn is_aborted(&self) -> bool {
+        let (ref lock, _) = *self.shared;
+        lock.lock().unwrap().aborted
+    }
+}
+
+impl Drop for BodyReceiver {
+    fn drop(&mut self) {
+        let (ref lock, ref condvar) = *self.shared;
+        let ready = {
+            let mut state = lock.lock().unwrap();
+            state.closed = true;
+            condvar.notify_all();
+            state.ready.take()
+        };
+
+        if let Some(ready) = ready {
+            ready();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use headers::HeaderMap;
+    use std::io::{ErrorKind, Read, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use super::{sink, stream};
+
+    #[test]
+    fn test_body_stream_reads_chunks() {
+        let (sender, mut body) = stream(1024, Box::new(|| ()));
+        sender.push("hello ".as_bytes().to_vec());
+        sender.push("world".as_bytes().to_vec());
+
+        assert_eq!(body.try_read(), Some("hello ".as_bytes().to_vec()));
+        assert!(body.trailers().is_none());
+
+        let mut trailers = HeaderMap::new();
+        trailers.append("Checksum", "abc");
+        sender.finish(trailers);
+
+        let mut data = Vec::new();
+        body.read_to_end(&mut data).unwrap();
+        assert_eq!(data, "world".as_bytes());
+        assert!(body.is_finished());
+        assert_eq!(body.trailers().unwrap().get("checksum"), Some("abc"));
+    }
+
+    #[test]
+    fn test_body_stream_blocking_read() {
+        let (sender, mut body) = stream(1024, Box::new(|| ()));
+        let reader = thread::spawn(move || {
+            let mut data = Vec::new();
+            body.read_to_end(&mut data).map(|_| data)
+        });
+
+        for chunk in vec!["a", "bc", "def"] {
+            sender.push(chunk.as_bytes().to_vec());
+        }
+        sender.finish(HeaderMap::new());
+
+        assert_eq!(reader.join().unwrap().unwrap(), "abcdef".as_bytes());
+    }
+
+    #[test]
+    fn test_body_stream_backpressure() {
+        let resumed = Arc::new(AtomicUsize::new(0));
+        let counter = resumed.clone();
+        let (sender, mut body) = stream(4, Box::new(move || { counter.fetch_add(1, Ordering::SeqCst); }));
+
+        sender.push("abc".as_bytes().to_vec());
+        assert!(!sender.is_full());
+        sender.push("def".as_bytes().to_vec());
+        assert!(sender.is_full());
+
+        // Still 4 bytes waiting after a short read
+        let mut buf = [0; 2];
+        assert_eq!(body.read(&mut buf).unwrap(), 2);
+        assert_eq!(resumed.load(Ordering::SeqCst), 0);
+
+        assert_eq!(body.read(&mut buf).unwrap(), 1);
+        assert_eq!(resumed.load(Ordering::SeqCst), 1);
+        assert!(!sender.is_full());
+    }
+
+    #[test]
+    fn test_body_stream_aborted() {
+        let (sender, mut body) = stream(1024, Box::new(|| ()));
+        sender.push("partial".as_bytes().to_vec());
+        drop(sender);
+
+        let mut data = Vec::new();
+        assert_eq!(body.read_to_end(&mut data).unwrap_err().kind(), ErrorKind::ConnectionAborted);
+        assert_eq!(data, "partial".as_bytes());
+        assert!(body.is_aborted());
+    }
+
+    #[test]
+    fn test_body_sink_sends_data_and_trailers() {
+        let woken = Arc::new(AtomicUsize::new(0));
+        let counter = woken.clone();
+        let (mut body, receiver) = sink("head ".as_bytes().to_vec());
+        receiver.attach(1024, Box::new(move || { counter.fetch_add(1, Ordering::SeqCst); }));
+
+        body.write_all("first".as_bytes()).unwrap();
+        body.send(" second".as_bytes()).unwrap();
+        assert_eq!(woken.load(Ordering::SeqCst), 1);
+        assert_eq!(receiver.take(), ("head first second".as_bytes().to_vec(), None));
+
+        let mut trailers = HeaderMap::new();
+        trailers.append("Rows", "2");
+        body.finish(trailers);
+
+        assert_eq!(woken.load(Ordering::SeqCst), 2);
+        assert!(receiver.is_ready());
+        assert_eq!(receiver.take().1.unwrap().get("rows"), Some("2"));
+        assert!(!receiver.is_aborted());
+    }
+
+    #[test]
+    fn test_body_sink_backpressure() {
+        let ready = Arc::new(AtomicUsize::new(0));
+        let counter = ready.clone();
+        let (body, receiver) = sink(Vec::new());
+        receiver.attach(4, Box::new(|| ()));
+        body.on_ready(move || { counter.fetch_add(1, Ordering::SeqCst); });
+
+        body.send("abcd".as_bytes()).unwrap();
+        assert!(!body.is_ready());
+        assert_eq!(ready.load(Ordering::SeqCst), 0);
+
+        receiver.take();
+        assert_eq!(ready.load(Ordering::SeqCst), 1);
+        assert!(body.is_ready());
+
+        // A blocked writer carries on once the receiver has taken the data
+        let mut body = body;
+        body.send("efgh".as_bytes()).unwrap();
+        let writer = thread::spawn(move || body.write_all("ijkl".as_bytes()).map(|_| body));
+
+        let mut data = Vec::new();
+        while data.len() < 8 {
+            data.extend(receiver.take().0);
+            thread::yield_now();
+        }
+
+        writer.join().unwrap().unwrap().finish(HeaderMap::new());
+        assert_eq!(data, "efghijkl".as_bytes());
+    }
+
+    #[test]
+    fn test_body_sink_aborted_and_closed() {
+        let (body, receiver) = sink(Vec::new());
+        drop(body);
+        assert!(receiver.is_aborted());
+
+        let (mut body, receiver) = sink(Vec::new());
+        drop(receiver);
+        assert!(body.is_ready());
+        assert_eq!(body.write("late".as_bytes()).unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,111 @@
+use promises::{self, Promise};
+use request::HttpRequest;
+use response::HttpResponse;
+
+/// Processes completed requests on behalf of the server.
+///
+/// Synchronous handlers return an already completed promise, while asynchronous
+/// handlers may hand a clone of the promise to another thread and complete it later.
+/// A failed promise is answered with `500 Internal Server Error`.
+pub trait Handler {
+    fn handle(&self, request: HttpRequest) -> Promise<HttpResponse>;
+
+    /// Called for requests sent with `Expect: 100-continue` before their body is read, with
+    /// the body left empty. Returning `None` sends `100 Continue` so the client sends the
+    /// body, returning a response such as `417` or `413` answers without reading it.
+    fn check_continue(&self, _request: &HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    /// Called once the headers of a request with a body are in. Returning true passes the
+    /// request to `handle` straight away, with the body arriving through
+    /// `HttpRequest::take_body_stream` instead of being buffered. `ParserLimits::max_body_size`
+    /// still applies, so raise it for large uploads.
+    fn stream_body(&self, _request: &HttpRequest) -> bool {
+        false
+    }
+}
+
+impl <F> Handler for F where F: Fn(HttpRequest) -> HttpResponse {
+    fn handle(&self, request: HttpRequest) -> Promise<HttpResponse> {
+        promises::completed(Ok(self(request)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::ByteBuf;
+    use promises::{self, Promise};
+    use request::{HttpRequest, HttpRequestBuilder, HttpResult, ParserLimits};
+    use response::HttpResponse;
+    use super::Handler;
+    use std::thread;
+
+    struct AsyncHandler;
+
+    struct UploadHandler;
+
+    impl Handler for UploadHandler {
+        fn handle(&self, _: HttpRequest) -> Promise<HttpResponse> {
+            promises::completed(Ok(HttpResponse::new(201)))
+        }
+
+        fn check_continue(&self, request: &HttpRequest) -> Option<HttpResponse> {
+            match request.header("Content-Length") {
+                Some("0") => None,
+                _ => Some(HttpResponse::new(413))
+            }
+        }
+    }
+
+    impl Handler for AsyncHandler {
+        fn handle(&self, _: HttpRequest) -> Promise<HttpResponse> {
+            let promise = promises::incomplete();
+            let completer = promise.clone();
+            thread::spawn(move || completer.complete(HttpResponse::new(202)));
+            promise
+        }
+    }
+
+    fn request() -> HttpRequest {
+        let buffer = ByteBuf::from_slice("GET / HTTP/1.1\r\n\r\n".as_bytes());
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Ok(HttpResult::Http1Request{request, ..}) => request,
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_closure_handler_is_completed() {
+        let handler = |_: HttpRequest| HttpResponse::new(200);
+        let promise = handler.handle(request());
+
+        match promise.take() {
+            Some(Ok(response)) => assert_eq!(response.status(), 200),
+            _ => panic!("Expected completed response")
+        }
+    }
+
+    #[test]
+    fn test_async_handler_completes_later() {
+        let promise = AsyncHandler.handle(request());
+        let (tx, rx) = ::std::sync::mpsc::channel();
+
+        promise.success(move |response: &HttpResponse| {
+                tx.send(response.status()).unwrap();
+            });
+
+        assert_eq!(rx.recv().unwrap(), 202);
+    }
+
+    #[test]
+    fn test_check_continue() {
+        let handler = |_: HttpRequest| HttpResponse::new(200);
+        assert!(handler.check_continue(&request()).is_none());
+
+        match UploadHandler.check_continue(&request()) {
+            Some(response) => assert_eq!(response.status(), 413),
+            None => panic!("Expected rejection")
+        }
+    }
+}
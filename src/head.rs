@@ -0,0 +1,336 @@
+use std::ascii::AsciiExt;
+use std::str;
+use request::{is_control_char, is_token_char, HttpError, HttpVersion, ParserLimits, ParserMode};
+
+/// A byte range within the buffer a `RequestHead` was parsed from.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct HeaderSpan {
+    pub name: Span,
+    pub value: Span
+}
+
+/// A request line and header block parsed without copying.
+///
+/// Only offsets are recorded while parsing, into a header slice supplied by the caller,
+/// and every accessor hands out a view into the original buffer. Nothing is allocated.
+/// The same `ParserLimits` and `ParserMode` rules as `HttpRequestBuilder` apply, except
+/// that obs-fold continuation lines are always rejected, since unfolding a value would
+/// need a copy.
+#[derive(Debug)]
+pub struct RequestHead<'b, 'h> {
+    buffer: &'b [u8],
+    method: Span,
+    target: Span,
+    version: HttpVersion,
+    headers: &'h [HeaderSpan],
+    length: usize
+}
+
+impl<'b, 'h> RequestHead<'b, 'h> {
+    /// Parses the head at the start of `buffer`, normally the `bytes()` of the connection's
+    /// read buffer. Returns `Ok(None)` until the blank line ending the headers has arrived,
+    /// in which case the caller reads more and parses again from the start.
+    ///
+    /// A request with more fields than `headers` can hold fails with `HeaderTooLarge`.
+    pub fn parse(buffer: &'b [u8], headers: &'h mut [HeaderSpan], limits: &ParserLimits, mode: ParserMode)
+            -> Result<Option<RequestHead<'b, 'h>>, HttpError> {
+        let strict = mode == ParserMode::Strict;
+        let mut position = 0;
+
+        // Empty lines before the request line are ignored, like the builder does
+        let mut request_line = try!(next_line(buffer, position, strict));
+        while let Some((line, next)) = request_line {
+            try!(limits.check_request_line(next - position));
+            if line.start != line.end {
+                break;
+            }
+
+            position = next;
+            request_line = try!(next_line(buffer, position, strict));
+        }
+
+        let (line, next) = match request_line {
+            Some(found) => found,
+            None => {
+                try!(limits.check_request_line(buffer.len() - position));
+                return Ok(None);
+            }
+        };
+
+        let (method, target, version) = try!(parse_request_line(buffer, line));
+        position = next;
+        let headers_start = position;
+
+        let mut count = 0;
+        loop {
+            let (line, next) = match try!(next_line(buffer, position, strict)) {
+                Some(found) => found,
+                None => {
+                    try!(limits.check_header_line(buffer.len() - position, buffer.len() - headers_start));
+                    return Ok(None);
+                }
+            };
+
+            try!(limits.check_header_line(next - position, next - headers_start));
+            position = next;
+            if line.start == line.end {
+                break;
+            }
+
+            if count == headers.len() {
+                return Err(HttpError::HeaderTooLarge);
+            }
+
+            headers[count] = try!(parse_header_line(buffer, line, strict));
+            count += 1;
+            try!(limits.check_header_count(count));
+        }
+
+        Ok(Some(RequestHead {
+            buffer: buffer,
+            method: method,
+            target: target,
+            version: version,
+            headers: &headers[.. count],
+            length: position
+        }))
+    }
+
+    pub fn method(&self) -> &'b str {
+        self.str_at(self.method)
+    }
+
+    /// The raw request target, still percent-encoded.
+    pub fn path(&self) -> &'b str {
+        self.str_at(self.target)
+    }
+
+    pub fn version(&self) -> HttpVersion {
+        self.version
+    }
+
+    pub fn headers(&self) -> Headers<'b, 'h> {
+        Headers {
+            buffer: self.buffer,
+            spans: self.headers.iter()
+        }
+    }
+
+    /// The value of the first header with the given name. Values are bytes since
+    /// obs-text is allowed in them.
+    pub fn header(&self, name: &str) -> Option<&'b [u8]> {
+        self.headers().find(|&(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    /// The number of bytes the head took up, which is where the body starts.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    fn str_at(&self, span: Span) -> &'b str {
+        // Spans handed out are only ever over validated ASCII or UTF-8
+        str::from_utf8(&self.buffer[span.start .. span.end]).unwrap_or("")
+    }
+}
+
+pub struct Headers<'b, 'h> {
+    buffer: &'b [u8],
+    spans: ::std::slice::Iter<'h, HeaderSpan>
+}
+
+impl<'b, 'h> Iterator for Headers<'b, 'h> {
+    type Item = (&'b str, &'b [u8]);
+
+    fn next(&mut self) -> Option<(&'b str, &'b [u8])> {
+        self.spans.next().map(|span| {
+            let name = str::from_utf8(&self.buffer[span.name.start .. span.name.end]).unwrap_or("");
+            (name, &self.buffer[span.value.start .. span.value.end])
+        })
+    }
+}
+
+/// Finds the line starting at `from`, returning its span without the line ending and
+/// the position of the following line. Lines end in CRLF, or also a bare LF when not
+/// `strict`. A CR anywhere else is refused in either mode.
+fn next_line(buffer: &[u8], from: usize, strict: bool) -> Result<Option<(Span, usize)>, HttpError> {
+    let newline = match buffer[from ..].iter().position(|&byte| byte == b'\n') {
+        Some(index) => from + index,
+        None => return Ok(None)
+    };
+
+    let end = match newline > from && buffer[newline - 1] == b'\r' {
+        true => newline - 1,
+        false if strict => return Err(HttpError::InvalidLineEnding),
+        false => newline
+    };
+
+    if buffer[from .. end].contains(&b'\r') {
+        return Err(HttpError::InvalidLineEnding);
+    }
+
+    Ok(Some((Span {start: from, end: end}, newline + 1)))
+}
+
+fn parse_request_line(buffer: &[u8], line: Span) -> Result<(Span, Span, HttpVersion), HttpError> {
+    let bytes = &buffer[line.start .. line.end];
+
+    let first = try!(bytes.iter().position(|&byte| byte == b' ').ok_or(HttpError::BadRequestLine));
+    let last = try!(bytes.iter().rposition(|&byte| byte == b' ').ok_or(HttpError::BadRequestLine));
+    if first == 0 || last <= first + 1 {
+        return Err(HttpError::BadRequestLine);
+    }
+
+    let method = &bytes[.. first];
+    if !method.iter().all(|&byte| is_token_char(byte)) {
+        return Err(HttpError::BadMethod(String::from_utf8_lossy(method).into_owned()));
+    }
+
+    let target = &bytes[first + 1 .. last];
+    if target.iter().any(|&byte| byte <= b' ' || byte == 0x7f) || str::from_utf8(target).is_err() {
+        return Err(HttpError::BadRequestLine);
+    }
+
+    let version = try!(str::from_utf8(&bytes[last + 1 ..]).map_err(|_| HttpError::BadRequestLine));
+    let version = try!(HttpVersion::parse(version));
+
+    Ok((Span {start: line.start, end: line.start + first},
+        Span {start: line.start + first + 1, end: line.start + last},
+        version))
+}
+
+fn parse_header_line(buffer: &[u8], line: Span, strict: bool) -> Result<HeaderSpan, HttpError> {
+    let bytes = &buffer[line.start .. line.end];
+    let malformed = || HttpError::MalformedHeader(String::from_utf8_lossy(bytes).into_owned());
+
+    let colon = try!(bytes.iter().position(|&byte| byte == b':').ok_or_else(&malformed));
+    if colon == 0 || !bytes[.. colon].iter().all(|&byte| is_token_char(byte)) {
+        return Err(malformed());
+    }
+
+    let value = &bytes[colon + 1 ..];
+    if strict && value.iter().any(|&byte| is_control_char(byte)) {
+        let name = String::from_utf8_lossy(&bytes[.. colon]).into_owned();
+        return Err(HttpError::MalformedHeader(format!("control character in {}", name)));
+    }
+
+    let is_whitespace = |byte: &u8| *byte == b' ' || *byte == b'\t';
+    let start = value.iter().position(|byte| !is_whitespace(byte)).unwrap_or(value.len());
+    let end = value.iter().rposition(|byte| !is_whitespace(byte)).map(|index| index + 1).unwrap_or(start);
+
+    let value_start = line.start + colon + 1;
+    Ok(HeaderSpan {
+        name: Span {start: line.start, end: line.start + colon},
+        value: Span {start: value_start + start, end: value_start + end}
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use request::{HttpError, HttpVersion, ParserLimits, ParserMode};
+    use super::{HeaderSpan, RequestHead};
+
+    #[test]
+    fn test_request_head_borrows_buffer() {
+        let data = "GET /index.html?a=1 HTTP/1.1\r\nHost: example.com\r\nAccept:  */* \r\n\r\nbody".as_bytes();
+        let mut headers = [HeaderSpan::default(); 8];
+        let head = RequestHead::parse(data, &mut headers, &ParserLimits::new(), ParserMode::Strict).unwrap().unwrap();
+
+        assert_eq!(head.method(), "GET");
+        assert_eq!(head.path(), "/index.html?a=1");
+        assert_eq!(head.version(), HttpVersion::Http11);
+        assert_eq!(head.header("host"), Some("example.com".as_bytes()));
+        assert_eq!(head.header("Accept"), Some("*/*".as_bytes()));
+        assert_eq!(head.headers().count(), 2);
+        assert_eq!(&data[head.len() ..], "body".as_bytes());
+
+        // Views point straight into the read buffer
+        assert_eq!(head.path().as_ptr(), data[4 ..].as_ptr());
+    }
+
+    #[test]
+    fn test_request_head_incomplete() {
+        let mut headers = [HeaderSpan::default(); 8];
+        for data in vec!["", "GET / HTTP/1.1", "GET / HTTP/1.1\r\nHost: a\r\n", "GET / HTTP/1.1\r\nHost: a\r\n\r"] {
+            assert!(RequestHead::parse(data.as_bytes(), &mut headers, &ParserLimits::new(), ParserMode::Strict).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_request_head_bare_newlines() {
+        let mut headers = [HeaderSpan::default(); 8];
+        let head = RequestHead::parse("\r\nPOST / HTTP/1.0\nContent-Length: 0\n\n".as_bytes(), &mut headers, &ParserLimits::new(), ParserMode::Lenient)
+            .unwrap().unwrap();
+
+        assert_eq!(head.method(), "POST");
+        assert_eq!(head.version(), HttpVersion::Http10);
+        assert_eq!(head.header("content-length"), Some("0".as_bytes()));
+    }
+
+    #[test]
+    fn test_request_head_errors() {
+        let requests = vec![
+            ("GET /\r\n\r\n", HttpError::BadRequestLine),
+            ("GET  HTTP/1.1\r\n\r\n", HttpError::BadRequestLine),
+            ("GE(T / HTTP/1.1\r\n\r\n", HttpError::BadMethod(String::from("GE(T"))),
+            ("GET / HTTP/3.0\r\n\r\n", HttpError::UnsupportedVersion(String::from("HTTP/3.0"))),
+            ("GET / HTTP/1.1\r\nHost : a\r\n\r\n", HttpError::MalformedHeader(String::from("Host : a"))),
+            ("GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n", HttpError::MalformedHeader(String::from(" folded"))),
+            ("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n", HttpError::HeaderTooLarge)
+        ];
+
+        for (data, error) in requests {
+            let mut headers = [HeaderSpan::default(); 2];
+            assert_eq!(RequestHead::parse(data.as_bytes(), &mut headers, &ParserLimits::new(), ParserMode::Lenient).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn test_request_head_strict_mode() {
+        let requests = vec![
+            ("GET / HTTP/1.1\nHost: a\r\n\r\n", HttpError::InvalidLineEnding),
+            ("GET / HTTP/1.1\r\nHost: a\n\r\n", HttpError::InvalidLineEnding),
+            ("GET / HTTP/1.1\r\nHost: a\rb\r\n\r\n", HttpError::InvalidLineEnding),
+            ("GET / HTTP/1.1\r\nHost: a\u{1}b\r\n\r\n", HttpError::MalformedHeader(String::from("control character in Host")))
+        ];
+
+        for (data, error) in requests {
+            let mut headers = [HeaderSpan::default(); 8];
+            assert_eq!(RequestHead::parse(data.as_bytes(), &mut headers, &ParserLimits::new(), ParserMode::Strict).unwrap_err(), error);
+        }
+
+        // A bare CR is never a line ending, a control character is only refused when strict
+        let mut headers = [HeaderSpan::default(); 8];
+        let lenient = ParserMode::Lenient;
+        assert_eq!(RequestHead::parse("GET / HTTP/1.1\rHost: a\n\n".as_bytes(), &mut headers, &ParserLimits::new(), lenient).unwrap_err(),
+            HttpError::InvalidLineEnding);
+        assert!(RequestHead::parse("GET / HTTP/1.1\nHost: a\u{1}b\n\n".as_bytes(), &mut headers, &ParserLimits::new(), lenient).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_request_head_limits() {
+        let limits = ParserLimits::new().max_request_line(16).max_header_size(12).max_headers_size(24).max_headers(2);
+        let requests = vec![
+            ("GET /abcdefgh HTTP/1.1\r\n\r\n", HttpError::UriTooLong),
+            ("GET /abcdefgh HTTP", HttpError::UriTooLong),
+            ("GET / HTTP/1.1\r\nHost: abcdefg\r\n\r\n", HttpError::HeaderTooLarge),
+            ("GET / HTTP/1.1\r\nHost: abcdefg", HttpError::HeaderTooLarge),
+            ("GET / HTTP/1.1\r\nA: 1234567\r\nB: 1234567\r\nC", HttpError::HeaderTooLarge),
+            ("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n", HttpError::HeaderTooLarge)
+        ];
+
+        for (data, error) in requests {
+            let mut headers = [HeaderSpan::default(); 8];
+            assert_eq!(RequestHead::parse(data.as_bytes(), &mut headers, &limits, ParserMode::Strict).unwrap_err(), error);
+        }
+
+        let mut headers = [HeaderSpan::default(); 8];
+        let data = "GET / HTTP/1.1\r\nHost: abcd\r\nA: 1\r\n\r\n".as_bytes();
+        assert!(RequestHead::parse(data, &mut headers, &limits, ParserMode::Strict).unwrap().is_some());
+    }
+}
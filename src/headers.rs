@@ -0,0 +1,142 @@
+use std::ascii::AsciiExt;
+use std::slice;
+
+/// Header fields in the order they were received or added.
+///
+/// Lookups ignore ASCII case but names keep the casing they were given, so a proxy can
+/// forward them untouched. Repeated fields such as `Set-Cookie` are kept as separate entries.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>
+}
+
+pub type Iter<'a> = slice::Iter<'a, (String, String)>;
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap {
+            entries: Vec::new()
+        }
+    }
+
+    /// Adds a field, keeping any existing fields with the same name.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((String::from(name), String::from(value)));
+    }
+
+    /// Replaces every field with the same name by a single new one.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Removes every field with the given name, returning whether any existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let length = self.entries.len();
+        self.entries.retain(|&(ref header, _)| !header.eq_ignore_ascii_case(name));
+        self.entries.len() != length
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// The value of the first field with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|&&(ref header, _)| header.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.as_ref())
+    }
+
+    /// The values of every field with the given name, in order.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|&&(ref header, _)| header.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.as_ref())
+            .collect()
+    }
+
+    /// Every value for the name combined into one comma separated list, which is how
+    /// repeated list-based fields like `Accept` are defined to be read.
+    pub fn get_joined(&self, name: &str) -> Option<String> {
+        match self.get_all(name) {
+            ref values if values.is_empty() => None,
+            values => Some(values.join(", "))
+        }
+    }
+
+    pub fn iter(&self) -> Iter {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = &'a (String, String);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeaderMap;
+
+    #[test]
+    fn test_header_map_case_insensitive() {
+        let mut headers = HeaderMap::new();
+        headers.append("Content-Type", "text/plain");
+
+        assert_eq!(headers.get("content-type"), Some("text/plain"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/plain"));
+        assert!(headers.contains("Content-type"));
+        assert_eq!(headers.get("Accept"), None);
+    }
+
+    #[test]
+    fn test_header_map_repeated_fields() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Accept", "text/html");
+        headers.append("set-cookie", "b=2");
+        headers.append("ACCEPT", "application/json;q=0.9");
+
+        assert_eq!(headers.get("Set-Cookie"), Some("a=1"));
+        assert_eq!(headers.get_all("Set-Cookie"), vec!["a=1", "b=2"]);
+        assert_eq!(headers.get_joined("Accept"), Some(String::from("text/html, application/json;q=0.9")));
+        assert_eq!(headers.get_joined("Host"), None);
+    }
+
+    #[test]
+    fn test_header_map_preserves_order_and_casing() {
+        let mut headers = HeaderMap::new();
+        headers.append("X-Custom-ID", "1");
+        headers.append("host", "example.com");
+        headers.append("x-custom-id", "2");
+
+        let names: Vec<&str> = headers.iter().map(|&(ref name, _)| name.as_ref()).collect();
+        assert_eq!(names, vec!["X-Custom-ID", "host", "x-custom-id"]);
+    }
+
+    #[test]
+    fn test_header_map_insert_and_remove() {
+        let mut headers = HeaderMap::new();
+        headers.append("Via", "1.1 a");
+        headers.append("Via", "1.1 b");
+        headers.insert("via", "1.1 c");
+
+        assert_eq!(headers.get_all("Via"), vec!["1.1 c"]);
+        assert!(headers.remove("VIA"));
+        assert!(!headers.remove("Via"));
+        assert!(headers.is_empty());
+    }
+}
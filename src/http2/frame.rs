@@ -0,0 +1,681 @@
+use std::cmp;
+use std::io::{self, ErrorKind};
+use std::mem;
+
+use bytes::{Buf, ByteBuf, MutBuf, MutByteBuf};
+use http2::ErrorCode;
+
+pub const FRAME_HEADER_LENGTH: usize = 9;
+
+/// The initial SETTINGS_MAX_FRAME_SIZE, which is also the smallest one allowed.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+
+/// The largest SETTINGS_MAX_FRAME_SIZE allowed.
+pub const MAX_FRAME_SIZE_LIMIT: u32 = 16_777_215;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+/// Stream dependency and weight, RFC 9113 section 5.3.2. `weight` is as sent, one less
+/// than the actual weight.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Priority {
+    pub exclusive: bool,
+    pub dependency: u32,
+    pub weight: u8
+}
+
+/// A frame from RFC 9113 section 6. Padding is given as the number of padding bytes, which
+/// still count towards flow control for DATA frames.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Frame {
+    Data {
+        stream_id: u32,
+        data: Vec<u8>,
+        end_stream: bool,
+        padding: Option<u8>
+    },
+    Headers {
+        stream_id: u32,
+        priority: Option<Priority>,
+        block: Vec<u8>,
+        end_stream: bool,
+        end_headers: bool,
+        padding: Option<u8>
+    },
+    Priority {
+        stream_id: u32,
+        priority: Priority
+    },
+    RstStream {
+        stream_id: u32,
+        error_code: ErrorCode
+    },
+    Settings {
+        ack: bool,
+        parameters: Vec<(u16, u32)>
+    },
+    PushPromise {
+        stream_id: u32,
+        promised_stream_id: u32,
+        block: Vec<u8>,
+        end_headers: bool,
+        padding: Option<u8>
+    },
+    Ping {
+        ack: bool,
+        data: [u8; 8]
+    },
+    GoAway {
+        last_stream_id: u32,
+        error_code: ErrorCode,
+        debug_data: Vec<u8>
+    },
+    WindowUpdate {
+        stream_id: u32,
+        increment: u32
+    },
+    Continuation {
+        stream_id: u32,
+        block: Vec<u8>,
+        end_headers: bool
+    },
+    //Frames of unknown types, which have to be ignored
+    Unknown {
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: Vec<u8>
+    }
+}
+
+impl Frame {
+    pub fn stream_id(&self) -> u32 {
+        match *self {
+            Frame::Data{stream_id, ..} |
+            Frame::Headers{stream_id, ..} |
+            Frame::Priority{stream_id, ..} |
+            Frame::RstStream{stream_id, ..} |
+            Frame::PushPromise{stream_id, ..} |
+            Frame::WindowUpdate{stream_id, ..} |
+            Frame::Continuation{stream_id, ..} |
+            Frame::Unknown{stream_id, ..} => stream_id,
+            Frame::Settings{..} | Frame::Ping{..} | Frame::GoAway{..} => 0
+        }
+    }
+
+    /// Writes the frame into `buffer`. Fails if the payload is larger than `max_frame_size`,
+    /// the SETTINGS_MAX_FRAME_SIZE of the peer, or if the buffer has no room for it.
+    pub fn encode(&self, buffer: &mut MutByteBuf, max_frame_size: u32) -> io::Result<usize> {
+        let data = try!(self.to_bytes(max_frame_size));
+        if buffer.remaining() < data.len() {
+            return Err(io::Error::new(ErrorKind::Other, "Frame does not fit in buffer"));
+        }
+
+        Ok(buffer.write_slice(&data))
+    }
+
+    /// The frame as sent. Fails if the payload is larger than `max_frame_size`, the
+    /// SETTINGS_MAX_FRAME_SIZE of the peer, since the length field can't be trusted beyond it.
+    pub fn to_bytes(&self, max_frame_size: u32) -> io::Result<Vec<u8>> {
+        let (kind, flags, payload) = match *self {
+            Frame::Data{ref data, end_stream, padding, ..} => {
+                let flags = flag(end_stream, END_STREAM) | flag(padding.is_some(), PADDED);
+                (DATA, flags, padded(data, padding))
+            },
+            Frame::Headers{priority, ref block, end_stream, end_headers, padding, ..} => {
+                let mut content = Vec::with_capacity(block.len() + 5);
+                if let Some(priority) = priority {
+                    write_priority(&mut content, priority);
+                }
+                content.extend(block.iter().cloned());
+
+                let flags = flag(end_stream, END_STREAM) | flag(end_headers, END_HEADERS) |
+                    flag(padding.is_some(), PADDED) | flag(priority.is_some(), PRIORITY_FLAG);
+                (HEADERS, flags, padded(&content, padding))
+            },
+            Frame::Priority{priority, ..} => {
+                let mut payload = Vec::with_capacity(5);
+                write_priority(&mut payload, priority);
+                (PRIORITY, 0, payload)
+            },
+            Frame::RstStream{error_code, ..} => {
+                let mut payload = Vec::with_capacity(4);
+                write_u32(&mut payload, error_code.code());
+                (RST_STREAM, 0, payload)
+            },
+            Frame::Settings{ack, ref parameters} => {
+                let mut payload = Vec::with_capacity(parameters.len() * 6);
+                for &(id, value) in parameters {
+                    payload.push((id >> 8) as u8);
+                    payload.push(id as u8);
+                    write_u32(&mut payload, value);
+                }
+                (SETTINGS, flag(ack, ACK), payload)
+            },
+            Frame::PushPromise{promised_stream_id, ref block, end_headers, padding, ..} => {
+                let mut content = Vec::with_capacity(block.len() + 4);
+                write_u32(&mut content, promised_stream_id & 0x7fff_ffff);
+                content.extend(block.iter().cloned());
+
+                let flags = flag(end_headers, END_HEADERS) | flag(padding.is_some(), PADDED);
+                (PUSH_PROMISE, flags, padded(&content, padding))
+            },
+            Frame::Ping{ack, data} => (PING, flag(ack, ACK), data.to_vec()),
+            Frame::GoAway{last_stream_id, error_code, ref debug_data} => {
+                let mut payload = Vec::with_capacity(8 + debug_data.len());
+                write_u32(&mut payload, last_stream_id & 0x7fff_ffff);
+                write_u32(&mut payload, error_code.code());
+                payload.extend(debug_data.iter().cloned());
+                (GOAWAY, 0, payload)
+            },
+            Frame::WindowUpdate{increment, ..} => {
+                let mut payload = Vec::with_capacity(4);
+                write_u32(&mut payload, increment & 0x7fff_ffff);
+                (WINDOW_UPDATE, 0, payload)
+            },
+            Frame::Continuation{ref block, end_headers, ..} => (CONTINUATION, flag(end_headers, END_HEADERS), block.clone()),
+            Frame::Unknown{kind, flags, ref payload, ..} => (kind, flags, payload.clone())
+        };
+
+        let length = payload.len();
+        if length > cmp::min(max_frame_size, MAX_FRAME_SIZE_LIMIT) as usize {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Frame exceeds the maximum frame size"));
+        }
+
+        let mut bytes = Vec::with_capacity(FRAME_HEADER_LENGTH + length);
+        bytes.push((length >> 16) as u8);
+        bytes.push((length >> 8) as u8);
+        bytes.push(length as u8);
+        bytes.push(kind);
+        bytes.push(flags);
+        write_u32(&mut bytes, self.stream_id() & 0x7fff_ffff);
+        bytes.extend(payload.into_iter());
+        Ok(bytes)
+    }
+}
+
+/// A malformed frame, RFC 9113 section 5.4.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FrameError {
+    //Ends the whole connection with GOAWAY
+    Connection(ErrorCode),
+    //Only ends the given stream with RST_STREAM, the frame has been skipped
+    Stream(u32, ErrorCode)
+}
+
+/// Splits bytes read from the connection into frames.
+///
+/// Reads may stop anywhere, a partial frame is kept until the rest of it arrives. Frames
+/// larger than the SETTINGS_MAX_FRAME_SIZE in effect are refused, as is anything but
+/// CONTINUATION frames for the same stream while a header block is unfinished.
+pub struct FrameDecoder {
+    max_frame_size: u32,
+    partial: Vec<u8>,
+    header_block: Option<u32>
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            partial: Vec::with_capacity(FRAME_HEADER_LENGTH),
+            header_block: None
+        }
+    }
+
+    /// Sets the largest frame accepted. A larger SETTINGS_MAX_FRAME_SIZE only applies once
+    /// the peer has acknowledged it.
+    pub fn set_max_frame_size(&mut self, size: u32) {
+        self.max_frame_size = cmp::min(cmp::max(size, DEFAULT_MAX_FRAME_SIZE), MAX_FRAME_SIZE_LIMIT);
+    }
+
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    /// Takes the next frame from `buffer`, or as much of it as has arrived. Returns `None`
+    /// once the buffer is used up without completing a frame.
+    pub fn decode(&mut self, buffer: &mut ByteBuf) -> Result<Option<Frame>, FrameError> {
+        loop {
+            let needed = match self.partial.len() < FRAME_HEADER_LENGTH {
+                true => FRAME_HEADER_LENGTH,
+                false => FRAME_HEADER_LENGTH + payload_length(&self.partial)
+            };
+
+            let length = cmp::min(needed - self.partial.len(), buffer.remaining());
+            if length > 0 {
+                self.partial.extend(buffer.bytes()[.. length].iter().cloned());
+                buffer.advance(length);
+            }
+
+            if self.partial.len() < needed {
+                return Ok(None);
+            }
+
+            if needed == FRAME_HEADER_LENGTH {
+                let length = payload_length(&self.partial);
+                if length > self.max_frame_size as usize {
+                    return Err(FrameError::Connection(ErrorCode::FrameSizeError));
+                }
+
+                if length > 0 {
+                    continue;
+                }
+            }
+
+            let frame = mem::replace(&mut self.partial, Vec::with_capacity(FRAME_HEADER_LENGTH));
+            return self.parse(&frame).map(Some);
+        }
+    }
+
+    fn parse(&mut self, frame: &[u8]) -> Result<Frame, FrameError> {
+        let kind = frame[3];
+        let flags = frame[4];
+        let stream_id = read_u32(&frame[5 .. 9]) & 0x7fff_ffff;
+
+        // A header block has to be finished before any other frame, RFC 9113 section 6.10
+        match self.header_block {
+            Some(expected) if kind != CONTINUATION || stream_id != expected => {
+                return Err(FrameError::Connection(ErrorCode::ProtocolError));
+            },
+            None if kind == CONTINUATION => return Err(FrameError::Connection(ErrorCode::ProtocolError)),
+            _ => ()
+        }
+
+        self.header_block = match kind {
+            HEADERS | PUSH_PROMISE | CONTINUATION if flags & END_HEADERS == 0 => Some(stream_id),
+            _ => None
+        };
+
+        parse_payload(kind, flags, stream_id, &frame[FRAME_HEADER_LENGTH ..])
+    }
+}
+
+fn parse_payload(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Result<Frame, FrameError> {
+    let on_stream = match kind {
+        DATA | HEADERS | PRIORITY | RST_STREAM | PUSH_PROMISE | CONTINUATION => Some(true),
+        SETTINGS | PING | GOAWAY => Some(false),
+        _ => None
+    };
+
+    match on_stream {
+        Some(true) if stream_id == 0 => return connection_error(ErrorCode::ProtocolError),
+        Some(false) if stream_id != 0 => return connection_error(ErrorCode::ProtocolError),
+        _ => ()
+    }
+
+    match kind {
+        DATA => {
+            let (data, padding) = try!(unpad(flags, payload));
+
+            Ok(Frame::Data {
+                stream_id: stream_id,
+                data: data.to_vec(),
+                end_stream: flags & END_STREAM != 0,
+                padding: padding
+            })
+        },
+        HEADERS => {
+            let (mut block, padding) = try!(unpad(flags, payload));
+
+            let priority = if flags & PRIORITY_FLAG != 0 {
+                if block.len() < 5 {
+                    return connection_error(ErrorCode::FrameSizeError);
+                }
+
+                let priority = read_priority(block);
+                block = &block[5 ..];
+                Some(priority)
+            } else {
+                None
+            };
+
+            // A stream can't depend on itself
+            if priority.map(|priority| priority.dependency) == Some(stream_id) {
+                return Err(FrameError::Stream(stream_id, ErrorCode::ProtocolError));
+            }
+
+            Ok(Frame::Headers {
+                stream_id: stream_id,
+                priority: priority,
+                block: block.to_vec(),
+                end_stream: flags & END_STREAM != 0,
+                end_headers: flags & END_HEADERS != 0,
+                padding: padding
+            })
+        },
+        PRIORITY => {
+            if payload.len() != 5 {
+                return Err(FrameError::Stream(stream_id, ErrorCode::FrameSizeError));
+            }
+
+            let priority = read_priority(payload);
+            if priority.dependency == stream_id {
+                return Err(FrameError::Stream(stream_id, ErrorCode::ProtocolError));
+            }
+
+            Ok(Frame::Priority{stream_id: stream_id, priority: priority})
+        },
+        RST_STREAM => {
+            if payload.len() != 4 {
+                return connection_error(ErrorCode::FrameSizeError);
+            }
+
+            Ok(Frame::RstStream{stream_id: stream_id, error_code: ErrorCode::from_code(read_u32(payload))})
+        },
+        SETTINGS => {
+            let ack = flags & ACK != 0;
+            if (ack && !payload.is_empty()) || payload.len() % 6 != 0 {
+                return connection_error(ErrorCode::FrameSizeError);
+            }
+
+            let parameters = payload.chunks(6).map(|parameter| {
+                    (((parameter[0] as u16) << 8) | parameter[1] as u16, read_u32(&parameter[2 ..]))
+                }).collect();
+
+            Ok(Frame::Settings{ack: ack, parameters: parameters})
+        },
+        PUSH_PROMISE => {
+            let (block, padding) = try!(unpad(flags, payload));
+            if block.len() < 4 {
+                return connection_error(ErrorCode::FrameSizeError);
+            }
+
+            Ok(Frame::PushPromise {
+                stream_id: stream_id,
+                promised_stream_id: read_u32(block) & 0x7fff_ffff,
+                block: block[4 ..].to_vec(),
+                end_headers: flags & END_HEADERS != 0,
+                padding: padding
+            })
+        },
+        PING => {
+            if payload.len() != 8 {
+                return connection_error(ErrorCode::FrameSizeError);
+            }
+
+            let mut data = [0; 8];
+            for (byte, value) in data.iter_mut().zip(payload) {
+                *byte = *value;
+            }
+
+            Ok(Frame::Ping{ack: flags & ACK != 0, data: data})
+        },
+        GOAWAY => {
+            if payload.len() < 8 {
+                return connection_error(ErrorCode::FrameSizeError);
+            }
+
+            Ok(Frame::GoAway {
+                last_stream_id: read_u32(payload) & 0x7fff_ffff,
+                error_code: ErrorCode::from_code(read_u32(&payload[4 ..])),
+                debug_data: payload[8 ..].to_vec()
+            })
+        },
+        WINDOW_UPDATE => {
+            if payload.len() != 4 {
+                return connection_error(ErrorCode::FrameSizeError);
+            }
+
+            let increment = read_u32(payload) & 0x7fff_ffff;
+            match (increment, stream_id) {
+                (0, 0) => connection_error(ErrorCode::ProtocolError),
+                (0, stream_id) => Err(FrameError::Stream(stream_id, ErrorCode::ProtocolError)),
+                _ => Ok(Frame::WindowUpdate{stream_id: stream_id, increment: increment})
+            }
+        },
+        CONTINUATION => Ok(Frame::Continuation {
+            stream_id: stream_id,
+            block: payload.to_vec(),
+            end_headers: flags & END_HEADERS != 0
+        }),
+        kind => Ok(Frame::Unknown {
+            kind: kind,
+            flags: flags,
+            stream_id: stream_id,
+            payload: payload.to_vec()
+        })
+    }
+}
+
+fn connection_error<T>(code: ErrorCode) -> Result<T, FrameError> {
+    Err(FrameError::Connection(code))
+}
+
+/// Strips the padding from a DATA, HEADERS or PUSH_PROMISE payload.
+fn unpad(flags: u8, payload: &[u8]) -> Result<(&[u8], Option<u8>), FrameError> {
+    if flags & PADDED == 0 {
+        return Ok((payload, None));
+    }
+
+    if payload.is_empty() {
+        return connection_error(ErrorCode::FrameSizeError);
+    }
+
+    let padding = payload[0];
+    let content = &payload[1 ..];
+    if padding as usize > content.len() {
+        return connection_error(ErrorCode::ProtocolError);
+    }
+
+    Ok((&content[.. content.len() - padding as usize], Some(padding)))
+}
+
+fn padded(content: &[u8], padding: Option<u8>) -> Vec<u8> {
+    match padding {
+        Some(padding) => {
+            let mut payload = Vec::with_capacity(1 + content.len() + padding as usize);
+            payload.push(padding);
+            payload.extend(content.iter().cloned());
+            payload.extend((0 .. padding).map(|_| 0));
+            payload
+        },
+        None => content.to_vec()
+    }
+}
+
+fn flag(set: bool, flag: u8) -> u8 {
+    match set {
+        true => flag,
+        false => 0
+    }
+}
+
+fn payload_length(header: &[u8]) -> usize {
+    ((header[0] as usize) << 16) | ((header[1] as usize) << 8) | header[2] as usize
+}
+
+fn read_priority(bytes: &[u8]) -> Priority {
+    let dependency = read_u32(bytes);
+
+    Priority {
+        exclusive: dependency & 0x8000_0000 != 0,
+        dependency: dependency & 0x7fff_ffff,
+        weight: bytes[4]
+    }
+}
+
+fn write_priority(output: &mut Vec<u8>, priority: Priority) {
+    let exclusive = match priority.exclusive {
+        true => 0x8000_0000,
+        false => 0
+    };
+
+    write_u32(output, exclusive | (priority.dependency & 0x7fff_ffff));
+    output.push(priority.weight);
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) | bytes[3] as u32
+}
+
+fn write_u32(output: &mut Vec<u8>, value: u32) {
+    output.push((value >> 24) as u8);
+    output.push((value >> 16) as u8);
+    output.push((value >> 8) as u8);
+    output.push(value as u8);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use bytes::{Buf, ByteBuf};
+    use http2::ErrorCode;
+    use super::{DEFAULT_MAX_FRAME_SIZE, MAX_FRAME_SIZE_LIMIT, Frame, FrameDecoder, FrameError, Priority};
+
+    fn frames() -> Vec<Frame> {
+        let priority = Priority {exclusive: true, dependency: 3, weight: 15};
+
+        vec![
+            Frame::Data {stream_id: 1, data: b"hello".to_vec(), end_stream: true, padding: Some(3)},
+            Frame::Headers {stream_id: 5, priority: Some(priority), block: vec![0x82, 0x84], end_stream: false, end_headers: false, padding: None},
+            Frame::Continuation {stream_id: 5, block: vec![0x86], end_headers: true},
+            Frame::Priority {stream_id: 7, priority: priority},
+            Frame::RstStream {stream_id: 1, error_code: ErrorCode::Cancel},
+            Frame::Settings {ack: false, parameters: vec![(0x3, 100), (0x4, 0x100000)]},
+            Frame::Settings {ack: true, parameters: vec![]},
+            Frame::PushPromise {stream_id: 1, promised_stream_id: 2, block: vec![0x82], end_headers: true, padding: Some(0)},
+            Frame::Ping {ack: false, data: [1, 2, 3, 4, 5, 6, 7, 8]},
+            Frame::GoAway {last_stream_id: 5, error_code: ErrorCode::EnhanceYourCalm, debug_data: b"slow down".to_vec()},
+            Frame::WindowUpdate {stream_id: 0, increment: 0x10000},
+            Frame::Unknown {kind: 0xfa, flags: 0x3, stream_id: 9, payload: vec![1, 2]}
+        ]
+    }
+
+    fn decode_all(data: &[u8]) -> Result<Vec<Frame>, FrameError> {
+        let mut decoder = FrameDecoder::new();
+        let mut buffer = ByteBuf::from_slice(data);
+        let mut frames = Vec::new();
+
+        while let Some(frame) = try!(decoder.decode(&mut buffer)) {
+            frames.push(frame);
+        }
+
+        Ok(frames)
+    }
+
+    /// Encodes a frame by hand, independently of `Frame::to_bytes`, with a payload of
+    /// less than 256 bytes. Also used by the connection tests.
+    pub fn frame(kind: u8, flags: u8, stream_id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0, 0, payload.len() as u8, kind, flags, 0, 0, 0, stream_id];
+        data.extend(payload.iter().cloned());
+        data
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut data = Vec::new();
+        for frame in frames() {
+            data.extend(frame.to_bytes(DEFAULT_MAX_FRAME_SIZE).unwrap());
+        }
+
+        assert_eq!(decode_all(&data).unwrap(), frames());
+
+        // Split at every possible point in turn
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        for byte in &data {
+            let mut buffer = ByteBuf::from_slice(&[*byte]);
+            if let Some(frame) = decoder.decode(&mut buffer).unwrap() {
+                decoded.push(frame);
+            }
+            assert!(!buffer.has_remaining());
+        }
+
+        assert_eq!(decoded, frames());
+    }
+
+    #[test]
+    fn test_frame_wire_format() {
+        let frame = Frame::Data {stream_id: 3, data: b"ab".to_vec(), end_stream: true, padding: Some(2)};
+        assert_eq!(frame.to_bytes(DEFAULT_MAX_FRAME_SIZE).unwrap(), vec![0, 0, 5, 0, 0x9, 0, 0, 0, 3, 2, b'a', b'b', 0, 0]);
+
+        let frame = Frame::Headers {stream_id: 1, priority: None, block: vec![0x82], end_stream: true, end_headers: true, padding: None};
+        assert_eq!(frame.to_bytes(DEFAULT_MAX_FRAME_SIZE).unwrap(), vec![0, 0, 1, 0x1, 0x5, 0, 0, 0, 1, 0x82]);
+    }
+
+    #[test]
+    fn test_frame_max_frame_size() {
+        let header = [0, 0x40, 0x01, 0, 0, 0, 0, 0, 1];
+        assert_eq!(decode_all(&header), Err(FrameError::Connection(ErrorCode::FrameSizeError)));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.set_max_frame_size(0x10000);
+        let mut buffer = ByteBuf::from_slice(&header);
+        assert_eq!(decoder.decode(&mut buffer), Ok(None));
+
+        let frame = Frame::Data {stream_id: 1, data: vec![0; 16_385], end_stream: false, padding: None};
+        let mut buffer = ByteBuf::mut_with_capacity(32 * 1024);
+        assert!(frame.encode(&mut buffer, 16_384).is_err());
+        assert_eq!(frame.encode(&mut buffer, 0x10000).unwrap(), 16_394);
+
+        let mut buffer = ByteBuf::mut_with_capacity(16);
+        assert!(frame.encode(&mut buffer, 0x10000).is_err());
+    }
+
+    #[test]
+    fn test_frame_oversized_payload() {
+        let frame = Frame::Data {stream_id: 1, data: vec![0; 16_385], end_stream: false, padding: None};
+        assert!(frame.to_bytes(DEFAULT_MAX_FRAME_SIZE).is_err());
+        assert_eq!(frame.to_bytes(0x10000).unwrap().len(), 16_394);
+
+        // The length field has 24 bits, whatever the peer asks for
+        let frame = Frame::Unknown {kind: 0xa, flags: 0, stream_id: 1, payload: vec![0; MAX_FRAME_SIZE_LIMIT as usize + 1]};
+        assert!(frame.to_bytes(u32::max_value()).is_err());
+    }
+
+    #[test]
+    fn test_frame_errors() {
+        let protocol_error = FrameError::Connection(ErrorCode::ProtocolError);
+        let frame_size_error = FrameError::Connection(ErrorCode::FrameSizeError);
+
+        let mut interrupted_block = frame(0x1, 0x1, 1, &[0x82]);
+        interrupted_block.extend(frame(0x4, 0x1, 0, &[]));
+
+        let cases = vec![
+            (frame(0x0, 0, 0, &[]), protocol_error),
+            (frame(0x6, 0, 1, &[0; 8]), protocol_error),
+            (frame(0x6, 0, 0, &[0; 4]), frame_size_error),
+            (frame(0x3, 0, 1, &[0; 2]), frame_size_error),
+            (frame(0x4, 0x1, 0, &[0; 6]), frame_size_error),
+            (frame(0x4, 0, 0, &[0; 5]), frame_size_error),
+            (frame(0x7, 0, 0, &[0; 4]), frame_size_error),
+            (frame(0x2, 0, 1, &[0; 3]), FrameError::Stream(1, ErrorCode::FrameSizeError)),
+            (frame(0x2, 0, 3, &[0, 0, 0, 3, 16]), FrameError::Stream(3, ErrorCode::ProtocolError)),
+            (frame(0x8, 0, 0, &[0; 4]), protocol_error),
+            (frame(0x8, 0, 5, &[0; 4]), FrameError::Stream(5, ErrorCode::ProtocolError)),
+            (frame(0x0, 0x8, 1, &[2, 0]), protocol_error),
+            (frame(0x9, 0x4, 1, &[]), protocol_error),
+            (interrupted_block, protocol_error)
+        ];
+
+        for (data, error) in cases {
+            assert_eq!(decode_all(&data), Err(error));
+        }
+    }
+
+    #[test]
+    fn test_frame_stream_error_skips_frame() {
+        let mut data = frame(0x2, 0, 1, &[0; 3]);
+        data.extend(Frame::Ping {ack: true, data: [0; 8]}.to_bytes(DEFAULT_MAX_FRAME_SIZE).unwrap());
+
+        let mut decoder = FrameDecoder::new();
+        let mut buffer = ByteBuf::from_slice(&data);
+        assert_eq!(decoder.decode(&mut buffer), Err(FrameError::Stream(1, ErrorCode::FrameSizeError)));
+        assert_eq!(decoder.decode(&mut buffer), Ok(Some(Frame::Ping {ack: true, data: [0; 8]})));
+    }
+}
@@ -0,0 +1,434 @@
+use std::mem;
+
+use bytes::{Buf, ByteBuf};
+
+pub use self::frame::{Frame, FrameDecoder, FrameError, Priority};
+pub use self::frame::{DEFAULT_MAX_FRAME_SIZE, FRAME_HEADER_LENGTH, MAX_FRAME_SIZE_LIMIT};
+
+mod frame;
+
+/// The part of the client connection preface after `PRI * HTTP/2.0\r\n\r\n`, which the
+/// HTTP/1 parser has already read as a request.
+pub const PREFACE_REMAINDER: &'static [u8] = b"SM\r\n\r\n";
+
+/// Error codes from RFC 9113 section 7, carried by RST_STREAM and GOAWAY frames.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ErrorCode {
+    NoError,
+    ProtocolError,
+    InternalError,
+    FlowControlError,
+    SettingsTimeout,
+    StreamClosed,
+    FrameSizeError,
+    RefusedStream,
+    Cancel,
+    CompressionError,
+    ConnectError,
+    EnhanceYourCalm,
+    InadequateSecurity,
+    Http11Required
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> u32 {
+        match *self {
+            ErrorCode::NoError => 0x0,
+            ErrorCode::ProtocolError => 0x1,
+            ErrorCode::InternalError => 0x2,
+            ErrorCode::FlowControlError => 0x3,
+            ErrorCode::SettingsTimeout => 0x4,
+            ErrorCode::StreamClosed => 0x5,
+            ErrorCode::FrameSizeError => 0x6,
+            ErrorCode::RefusedStream => 0x7,
+            ErrorCode::Cancel => 0x8,
+            ErrorCode::CompressionError => 0x9,
+            ErrorCode::ConnectError => 0xa,
+            ErrorCode::EnhanceYourCalm => 0xb,
+            ErrorCode::InadequateSecurity => 0xc,
+            ErrorCode::Http11Required => 0xd
+        }
+    }
+
+    /// Unknown codes are treated as `INTERNAL_ERROR`, which RFC 9113 section 7 allows.
+    pub fn from_code(code: u32) -> ErrorCode {
+        match code {
+            0x0 => ErrorCode::NoError,
+            0x1 => ErrorCode::ProtocolError,
+            0x3 => ErrorCode::FlowControlError,
+            0x4 => ErrorCode::SettingsTimeout,
+            0x5 => ErrorCode::StreamClosed,
+            0x6 => ErrorCode::FrameSizeError,
+            0x7 => ErrorCode::RefusedStream,
+            0x8 => ErrorCode::Cancel,
+            0x9 => ErrorCode::CompressionError,
+            0xa => ErrorCode::ConnectError,
+            0xb => ErrorCode::EnhanceYourCalm,
+            0xc => ErrorCode::InadequateSecurity,
+            0xd => ErrorCode::Http11Required,
+            _ => ErrorCode::InternalError
+        }
+    }
+}
+
+/// Connection parameters, RFC 9113 section 6.5.2. `None` stands for the values the
+/// protocol leaves unlimited until they are set.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Settings {
+    pub header_table_size: u32,
+    pub enable_push: bool,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    pub max_header_list_size: Option<u32>
+}
+
+impl Settings {
+    /// The values in effect before any SETTINGS frame has been received.
+    pub fn new() -> Settings {
+        Settings {
+            header_table_size: 4096,
+            enable_push: true,
+            max_concurrent_streams: None,
+            initial_window_size: 65_535,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_header_list_size: None
+        }
+    }
+
+    /// Applies the parameters of a SETTINGS frame in order, skipping unknown ones as the
+    /// protocol requires.
+    pub fn apply(&mut self, parameters: &[(u16, u32)]) -> Result<(), ErrorCode> {
+        for &(id, value) in parameters {
+            match id {
+                0x1 => self.header_table_size = value,
+                0x2 => self.enable_push = match value {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(ErrorCode::ProtocolError)
+                },
+                0x3 => self.max_concurrent_streams = Some(value),
+                0x4 if value > 0x7fff_ffff => return Err(ErrorCode::FlowControlError),
+                0x4 => self.initial_window_size = value,
+                0x5 if value < DEFAULT_MAX_FRAME_SIZE || value > MAX_FRAME_SIZE_LIMIT => return Err(ErrorCode::ProtocolError),
+                0x5 => self.max_frame_size = value,
+                0x6 => self.max_header_list_size = Some(value),
+                _ => ()
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The parameters of a SETTINGS frame holding every value that differs from the
+    /// protocol defaults.
+    pub fn parameters(&self) -> Vec<(u16, u32)> {
+        let defaults = Settings::new();
+        let mut parameters = Vec::new();
+
+        if self.header_table_size != defaults.header_table_size {
+            parameters.push((0x1, self.header_table_size));
+        }
+        if !self.enable_push {
+            parameters.push((0x2, 0));
+        }
+        if let Some(streams) = self.max_concurrent_streams {
+            parameters.push((0x3, streams));
+        }
+        if self.initial_window_size != defaults.initial_window_size {
+            parameters.push((0x4, self.initial_window_size));
+        }
+        if self.max_frame_size != defaults.max_frame_size {
+            parameters.push((0x5, self.max_frame_size));
+        }
+        if let Some(size) = self.max_header_list_size {
+            parameters.push((0x6, size));
+        }
+
+        parameters
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    //Waiting for the rest of the client preface
+    Preface,
+    //Waiting for the SETTINGS frame that ends the client preface
+    Settings,
+    Open,
+    Closed
+}
+
+/// The server side of an HTTP/2 connection started with prior knowledge, RFC 9113 section 3.3.
+///
+/// Buffers read from the socket, starting with whatever the HTTP/1 parser left after the
+/// `PRI` request, go into `receive` and everything from `take_output` is written back.
+/// The rest of the preface is checked and SETTINGS are exchanged here. Streams aren't
+/// served yet, so each one the client opens is refused with `REFUSED_STREAM`, which tells
+/// the client it is safe to retry the request elsewhere.
+pub struct Http2Connection {
+    state: State,
+    preface_read: usize,
+    decoder: FrameDecoder,
+    output: Vec<u8>,
+    local: Settings,
+    remote: Settings,
+    settings_acknowledged: bool,
+    highest_stream_id: u32
+}
+
+impl Http2Connection {
+    /// Queues the server's SETTINGS frame, which has to be the first frame it sends.
+    pub fn new(settings: Settings) -> Http2Connection {
+        let parameters = settings.parameters();
+        let mut connection = Http2Connection {
+            state: State::Preface,
+            preface_read: 0,
+            decoder: FrameDecoder::new(),
+            output: Vec::new(),
+            local: settings,
+            remote: Settings::new(),
+            settings_acknowledged: false,
+            highest_stream_id: 0
+        };
+
+        connection.send(Frame::Settings{ack: false, parameters: parameters});
+        connection
+    }
+
+    /// Processes bytes from the client, which may stop anywhere, using up all of `buffer`.
+    /// A connection error queues a GOAWAY carrying its code and closes the connection.
+    pub fn receive(&mut self, buffer: &mut ByteBuf) -> Result<(), ErrorCode> {
+        let result = match self.state {
+            State::Closed => Ok(()),
+            _ => self.process(buffer)
+        };
+
+        // Nothing is read after GOAWAY
+        let remaining = buffer.remaining();
+        buffer.advance(remaining);
+
+        if let Err(code) = result {
+            self.go_away(code);
+        }
+        result
+    }
+
+    /// Queues a GOAWAY frame and stops processing input, for a connection error or when
+    /// the server is shutting down.
+    pub fn go_away(&mut self, code: ErrorCode) {
+        if self.state == State::Closed {
+            return;
+        }
+
+        // Every stream so far has been refused, so none was processed
+        self.send(Frame::GoAway{last_stream_id: 0, error_code: code, debug_data: Vec::new()});
+        self.state = State::Closed;
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.output, Vec::new())
+    }
+
+    /// Whether either side has sent GOAWAY, after which nothing more is read.
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    pub fn remote_settings(&self) -> &Settings {
+        &self.remote
+    }
+
+    /// Whether the client has acknowledged the server's SETTINGS.
+    pub fn is_settings_acknowledged(&self) -> bool {
+        self.settings_acknowledged
+    }
+
+    fn process(&mut self, buffer: &mut ByteBuf) -> Result<(), ErrorCode> {
+        while self.state == State::Preface {
+            match buffer.read_byte() {
+                Some(byte) if byte == PREFACE_REMAINDER[self.preface_read] => self.preface_read += 1,
+                Some(_) => return Err(ErrorCode::ProtocolError),
+                None => return Ok(())
+            }
+
+            if self.preface_read == PREFACE_REMAINDER.len() {
+                self.state = State::Settings;
+            }
+        }
+
+        while self.state != State::Closed {
+            let frame = match self.decoder.decode(buffer) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(FrameError::Stream(stream_id, code)) => {
+                    // The client preface has to end with a valid SETTINGS frame
+                    if self.state == State::Settings {
+                        return Err(ErrorCode::ProtocolError);
+                    }
+
+                    self.send(Frame::RstStream{stream_id: stream_id, error_code: code});
+                    continue;
+                },
+                Err(FrameError::Connection(code)) => return Err(code)
+            };
+
+            try!(self.handle_frame(frame));
+        }
+
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), ErrorCode> {
+        // The client preface ends with a SETTINGS frame, which can't be an acknowledgement
+        if self.state == State::Settings {
+            match frame {
+                Frame::Settings{ack: false, ..} => (),
+                _ => return Err(ErrorCode::ProtocolError)
+            }
+        }
+
+        match frame {
+            Frame::Settings{ack: true, ..} => {
+                // Our own limits only apply once the client has seen them
+                self.settings_acknowledged = true;
+                self.decoder.set_max_frame_size(self.local.max_frame_size);
+            },
+            Frame::Settings{ack: false, parameters} => {
+                try!(self.remote.apply(&parameters));
+                self.send(Frame::Settings{ack: true, parameters: Vec::new()});
+                self.state = State::Open;
+            },
+            Frame::Ping{ack: false, data} => self.send(Frame::Ping{ack: true, data: data}),
+            Frame::GoAway{..} => self.state = State::Closed,
+            Frame::Headers{stream_id, ..} => {
+                // Client streams have odd, increasing identifiers
+                if stream_id % 2 == 0 || stream_id <= self.highest_stream_id {
+                    return Err(ErrorCode::ProtocolError);
+                }
+
+                self.highest_stream_id = stream_id;
+                self.send(Frame::RstStream{stream_id: stream_id, error_code: ErrorCode::RefusedStream});
+            },
+            // Only servers push
+            Frame::PushPromise{..} => return Err(ErrorCode::ProtocolError),
+            // Frames for refused streams and anything else are ignored until streams are served
+            _ => ()
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, frame: Frame) {
+        match frame.to_bytes(self.remote.max_frame_size) {
+            Ok(bytes) => self.output.extend(bytes.into_iter()),
+            Err(e) => error!("Could not send HTTP/2 frame {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::ByteBuf;
+    use super::{ErrorCode, Http2Connection, Settings};
+    use super::frame::tests::frame;
+
+    const EMPTY_SETTINGS: [u8; 9] = [0, 0, 0, 0x4, 0, 0, 0, 0, 0];
+    const SETTINGS_ACK: [u8; 9] = [0, 0, 0, 0x4, 0x1, 0, 0, 0, 0];
+
+    fn receive(connection: &mut Http2Connection, data: &[u8]) -> Result<(), ErrorCode> {
+        connection.receive(&mut ByteBuf::from_slice(data))
+    }
+
+    fn open_connection() -> Http2Connection {
+        let mut connection = Http2Connection::new(Settings::new());
+        let mut data = "SM\r\n\r\n".as_bytes().to_vec();
+        data.extend(EMPTY_SETTINGS.iter().cloned());
+
+        receive(&mut connection, &data).unwrap();
+        connection.take_output();
+        connection
+    }
+
+    #[test]
+    fn test_http2_settings_exchange() {
+        let mut connection = Http2Connection::new(Settings::new());
+        assert_eq!(connection.take_output(), EMPTY_SETTINGS.to_vec());
+
+        // Initial window size of 1 MiB, then an unknown parameter which is skipped
+        let mut data = "SM\r\n\r\n".as_bytes().to_vec();
+        data.extend(frame(0x4, 0, 0, &[0, 0x4, 0, 0x10, 0, 0, 0, 0x99, 0, 0, 0, 1]));
+        data.extend(SETTINGS_ACK.iter().cloned());
+
+        // Split at every possible point in turn
+        for byte in &data {
+            receive(&mut connection, &[*byte]).unwrap();
+        }
+
+        assert_eq!(connection.take_output(), SETTINGS_ACK.to_vec());
+        assert_eq!(connection.remote_settings().initial_window_size, 0x100000);
+        assert!(connection.is_settings_acknowledged());
+        assert!(!connection.is_closed());
+    }
+
+    #[test]
+    fn test_http2_local_settings_payload() {
+        let settings = Settings {enable_push: false, max_concurrent_streams: Some(100), ..Settings::new()};
+        assert_eq!(settings.parameters(), vec![(0x2, 0), (0x3, 100)]);
+
+        let mut applied = Settings::new();
+        applied.apply(&settings.parameters()).unwrap();
+        assert_eq!(applied, settings);
+    }
+
+    #[test]
+    fn test_http2_invalid_preface() {
+        let goaway = [0, 0, 8, 0x7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1];
+
+        let mut connection = Http2Connection::new(Settings::new());
+        connection.take_output();
+        assert_eq!(receive(&mut connection, "SM\r\nX".as_bytes()), Err(ErrorCode::ProtocolError));
+        assert_eq!(connection.take_output(), goaway.to_vec());
+        assert!(connection.is_closed());
+
+        // The preface has to end with SETTINGS rather than any other frame
+        let mut connection = Http2Connection::new(Settings::new());
+        let mut data = "SM\r\n\r\n".as_bytes().to_vec();
+        data.extend(frame(0x6, 0, 0, &[0; 8]));
+        assert_eq!(receive(&mut connection, &data), Err(ErrorCode::ProtocolError));
+    }
+
+    #[test]
+    fn test_http2_ping_and_refused_streams() {
+        let mut connection = open_connection();
+        let mut data = frame(0x6, 0, 0, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        data.extend(frame(0x1, 0x5, 1, &[0x82]));
+
+        receive(&mut connection, &data).unwrap();
+
+        let mut expected = frame(0x6, 0x1, 0, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        expected.extend(frame(0x3, 0, 1, &[0, 0, 0, 0x7]));
+        assert_eq!(connection.take_output(), expected);
+
+        // Stream identifiers can't go backwards
+        assert_eq!(receive(&mut connection, &frame(0x1, 0x5, 1, &[0x82])), Err(ErrorCode::ProtocolError));
+    }
+
+    #[test]
+    fn test_http2_settings_errors() {
+        let settings = vec![
+            (vec![0, 0x2, 0, 0, 0, 2], ErrorCode::ProtocolError),
+            (vec![0, 0x4, 0x80, 0, 0, 0], ErrorCode::FlowControlError),
+            (vec![0, 0x5, 0, 0, 0x10, 0], ErrorCode::ProtocolError),
+            (vec![0, 0x5], ErrorCode::FrameSizeError)
+        ];
+
+        for (payload, code) in settings {
+            let mut connection = open_connection();
+            assert_eq!(receive(&mut connection, &frame(0x4, 0, 0, &payload)), Err(code));
+        }
+
+        let mut connection = open_connection();
+        assert_eq!(receive(&mut connection, &[0, 0x40, 0x01, 0, 0, 0, 0, 0, 1]), Err(ErrorCode::FrameSizeError));
+    }
+}
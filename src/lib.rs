@@ -1,4 +1,34 @@
+#![feature(unboxed_closures, fnbox, drain, core)]
 extern crate mio;
+extern crate bytes;
+extern crate core;
+#[macro_use]
+extern crate log;
+extern crate threadpool;
+#[cfg(test)]
+extern crate libc;
+
+mod body;
+mod handler;
+mod head;
+mod headers;
+pub mod http2;
+mod request;
+mod processor;
+pub mod promises;
+mod response;
+mod server;
+mod uri;
+
+pub use body::{BodySink, BodyStream};
+pub use handler::Handler;
+pub use head::{HeaderSpan, RequestHead, Span};
+pub use headers::HeaderMap;
+pub use promises::Promise;
+pub use request::{HttpError, HttpMethod, HttpRequest, HttpRequestBuilder, HttpResult, HttpVersion, ParserLimits, ParserMode};
+pub use response::HttpResponse;
+pub use server::{Listening, Server, ShutdownHandle};
+pub use uri::{TargetForm, Uri};
 
 #[test]
 fn it_works() {
@@ -1,6 +1,7 @@
 use std::error::Error;
 use std::boxed::FnBox;
-use std::sync::mpsc::{Receiver, Sender, RecvError, channel};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 
 #[derive(Clone)]
 pub enum ExecutionContext {
@@ -25,95 +26,198 @@ pub fn incomplete<A>() -> Promise<A>{
     PromiseFactory{execution_context: ExecutionContext::ImmediateContext}.incomplete()
 }
 
-pub fn completed<A>(a: Result<A, Box<Error>>) -> Promise<A> {
+pub fn completed<A>(a: Result<A, Box<Error + Send>>) -> Promise<A> {
     PromiseFactory{execution_context: ExecutionContext::ImmediateContext}.completed(a)
 }
 
 impl PromiseFactory {
     pub fn incomplete<A>(&self) -> Promise<A> {
-        Promise{
-            data: None,
-            success_callbacks: Vec::with_capacity(1),
-            failure_callbacks: Vec::with_capacity(1),
-            execution_context: self.execution_context.clone()
-        }
+        self.create(None)
     }
 
-    pub fn completed<A>(&self, a: Result<A, Box<Error>>) -> Promise<A> {
+    pub fn completed<A>(&self, a: Result<A, Box<Error + Send>>) -> Promise<A> {
+        self.create(Some(a))
+    }
+
+    fn create<A>(&self, data: Option<Result<A, Box<Error + Send>>>) -> Promise<A> {
         Promise{
-            data: Some(a),
-            success_callbacks: Vec::with_capacity(1),
-            failure_callbacks: Vec::with_capacity(1),
+            state: Arc::new((Mutex::new(PromiseState {
+                data: data,
+                lent: false,
+                success_callbacks: Vec::with_capacity(1),
+                failure_callbacks: Vec::with_capacity(1),
+                complete_callbacks: Vec::new()
+            }), Condvar::new())),
             execution_context: self.execution_context.clone()
         }
     }
 }
 
-struct Promise<A: Sized> {
-    data: Option<Result<A, Box<Error>>>,
-    success_callbacks: Vec<Box<FnBox(&A)>>,
-    failure_callbacks : Vec<Box<FnBox(&Error)>>,
+struct PromiseState<A: Sized> {
+    data: Option<Result<A, Box<Error + Send>>>,
+    //Set while the value is out with callbacks registered after completion
+    lent: bool,
+    success_callbacks: Vec<Box<FnBox(&A) + Send>>,
+    failure_callbacks : Vec<Box<FnBox(&Error) + Send>>,
+    complete_callbacks: Vec<Box<FnBox() + Send>>
+}
+
+/// A handle to a value which may be completed later, possibly from another thread.
+///
+/// Clones share the same underlying state, so one copy can be handed to a worker to
+/// `complete` while another is returned to the caller.
+pub struct Promise<A: Sized> {
+    state: Arc<(Mutex<PromiseState<A>>, Condvar)>,
     execution_context: ExecutionContext
 }
 
+impl <A:Sized> Clone for Promise<A> {
+    fn clone(&self) -> Promise<A> {
+        Promise {
+            state: self.state.clone(),
+            execution_context: self.execution_context.clone()
+        }
+    }
+}
+
 impl <A:Sized> Promise<A> {
-    pub fn success<F>(&mut self, on_success: F) where F: FnOnce(&A)->() + Send + 'static {
-        match self.data {
-            Some(Ok(ref d)) => {
-                on_success(d)
-            },
-            Some(Err(_))=> (),
-            None => {
-                self.success_callbacks.push(Box::new(on_success))
+    pub fn incomplete() -> Promise<A> {
+        incomplete()
+    }
+
+    pub fn completed(a: Result<A, Box<Error + Send>>) -> Promise<A> {
+        completed(a)
+    }
+
+    pub fn success<F>(&self, on_success: F) where F: FnOnce(&A)->() + Send + 'static {
+        let data = {
+            let mut state = self.lock();
+            match state.data {
+                Some(Ok(_)) => {
+                    state.lent = true;
+                    state.data.take().unwrap()
+                },
+                Some(Err(_))=> return,
+                None => return state.success_callbacks.push(Box::new(on_success))
             }
+        };
+
+        // The value is lent out so the callback can run without the lock, `take` waits for it
+        if let Ok(ref d) = data {
+            on_success(d);
         }
+        self.settle(data);
     }
 
-    pub fn failure<F>(&mut self, on_failure: F) where F: FnOnce(&Error) + Send + 'static {
-        match self.data {
-            Some(Err(ref e)) => {
-                let error = & **e;
-                on_failure(error)
-            },
-            Some(Ok(_)) => (),
-            None => {
-                self.failure_callbacks.push(Box::new(on_failure))
+    pub fn failure<F>(&self, on_failure: F) where F: FnOnce(&Error) + Send + 'static {
+        let data = {
+            let mut state = self.lock();
+            match state.data {
+                Some(Err(_)) => {
+                    state.lent = true;
+                    state.data.take().unwrap()
+                },
+                Some(Ok(_)) => return,
+                None => return state.failure_callbacks.push(Box::new(on_failure))
             }
+        };
+
+        if let Err(ref e) = data {
+            on_failure(& **e);
         }
+        self.settle(data);
     }
 
-    pub fn complete(&mut self, a: A) {
-        let callbacks = self.success_callbacks.len();
-        let range = 0 .. callbacks;
-        for f in self.success_callbacks.drain(range) {
-            f.call_once((&a,))
+    /// Calls `on_complete` once the promise has a value or an error, after it has been stored
+    /// so `take` is sure to find it.
+    pub fn on_complete<F>(&self, on_complete: F) where F: FnOnce() + Send + 'static {
+        {
+            let mut state = self.lock();
+            if state.data.is_none() && !state.lent {
+                return state.complete_callbacks.push(Box::new(on_complete));
+            }
         }
 
-        self.data = Some(Ok(a));
+        on_complete()
+    }
+
+    pub fn complete(&self, a: A) {
+        self.settle(Ok(a))
     }
 
-    pub fn fail(&mut self, err: Box<Error>) {
+    pub fn fail(&self, err: Box<Error + Send>) {
+        self.settle(Err(err))
+    }
 
-        let callbacks = self.failure_callbacks.len();
-        let range = 0 .. callbacks;
+    fn lock(&self) -> MutexGuard<PromiseState<A>> {
+        let (ref lock, _) = *self.state;
+        lock.lock().unwrap()
+    }
 
-        print!("Invoking error callback {:?}", callbacks);
+    /// Runs the callbacks waiting on `data` and then stores it. Callbacks are called without
+    /// the lock held so they can use the promise themselves, which means any they register
+    /// are run here in turn.
+    fn settle(&self, data: Result<A, Box<Error + Send>>) {
+        loop {
+            let (success_callbacks, failure_callbacks) = {
+                let mut state = self.lock();
+                let success_callbacks = mem::replace(&mut state.success_callbacks, Vec::new());
+                let failure_callbacks = mem::replace(&mut state.failure_callbacks, Vec::new());
+
+                if success_callbacks.is_empty() && failure_callbacks.is_empty() {
+                    state.data = Some(data);
+                    state.lent = false;
+                    let (_, ref condvar) = *self.state;
+                    condvar.notify_all();
+                    let complete_callbacks = mem::replace(&mut state.complete_callbacks, Vec::new());
+                    drop(state);
+
+                    for f in complete_callbacks {
+                        f.call_once(())
+                    }
+                    return;
+                }
+
+                (success_callbacks, failure_callbacks)
+            };
+
+            match data {
+                Ok(ref a) => for f in success_callbacks {
+                    f.call_once((a,))
+                },
+                Err(ref err) => for f in failure_callbacks {
+                    let error_pointer = & **err;
+                    f.call_once((error_pointer,))
+                }
+            }
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        let state = self.lock();
+        state.data.is_some() || state.lent
+    }
 
-        for f in self.failure_callbacks.drain(range) {
-            let error_pointer = & * err;
-            f.call_once((error_pointer,))
+    /// Removes the completed value, leaving the promise incomplete again. Waits while a
+    /// callback registered after completion has the value, so must not be called from one.
+    pub fn take(&self) -> Option<Result<A, Box<Error + Send>>> {
+        let (ref lock, ref condvar) = *self.state;
+        let mut state = lock.lock().unwrap();
+        while state.lent {
+            state = condvar.wait(state).unwrap();
         }
 
-        self.data = Some(Err(err));
+        state.data.take()
     }
 
-    pub fn map<F,B>(&mut self, map: F) -> Promise<B> where
+    pub fn map<F,B>(&self, map: F) -> Promise<B> where
         F : FnOnce(&A) -> B + Send + 'static,
-        B : Send {
+        B : Send + 'static {
 
-        let mut p = incomplete();
+        let p = incomplete();
+        let mapped = p.clone();
         self.success(move |a| {
-                p.complete(map(a))
+                mapped.complete(map(a))
             });
         p
     }
@@ -124,6 +228,8 @@ mod test {
     use super::Promise;
     use std::error::Error;
     use std::sync::mpsc::{Receiver, Sender, RecvError, channel};
+    use std::thread;
+    use std::time::Duration;
     use std::fmt::{Display, Formatter};
     use core::fmt::Error as FmtError;
 
@@ -206,4 +312,73 @@ mod test {
 
         assert_eq!(rx.recv().unwrap(), "Error");
     }
+
+    #[test]
+    fn test_promise_callback_uses_promise() {
+        let promise = Promise::incomplete();
+        let inner = promise.clone();
+        let (tx, rx): (Sender<i32>, Receiver<i32>) = channel();
+
+        promise.success(move |d: &i32| {
+                let first = *d;
+                assert!(!inner.is_complete());
+                inner.success(move |d| {
+                        tx.send(first + *d).unwrap();
+                    });
+            });
+
+        promise.complete(1);
+
+        assert_eq!(rx.recv().unwrap(), 2);
+        assert_eq!(promise.take().and_then(|result| result.ok()), Some(1));
+    }
+
+    #[test]
+    fn test_promise_completed_callback_uses_promise() {
+        let promise = Promise::completed(Ok(1));
+        let inner = promise.clone();
+        let (tx, rx): (Sender<bool>, Receiver<bool>) = channel();
+
+        promise.success(move |_| {
+                tx.send(inner.is_complete()).unwrap();
+            });
+
+        assert_eq!(rx.recv().unwrap(), true);
+        assert!(promise.is_complete());
+    }
+
+    #[test]
+    fn test_promise_take_while_callback_runs() {
+        let promise = Promise::completed(Ok(1));
+        let lender = promise.clone();
+        let (tx, rx): (Sender<()>, Receiver<()>) = channel();
+
+        // The value must still be found while another thread's callback has it
+        let callback = thread::spawn(move || {
+            lender.success(move |_| {
+                    tx.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(50));
+                });
+        });
+
+        rx.recv().unwrap();
+        assert!(promise.is_complete());
+        assert_eq!(promise.take().and_then(|result| result.ok()), Some(1));
+        callback.join().unwrap();
+    }
+
+    #[test]
+    fn test_promise_on_complete() {
+        let promise : Promise<u32> = Promise::incomplete();
+        let inner = promise.clone();
+        let (tx, rx): (Sender<bool>, Receiver<bool>) = channel();
+
+        promise.on_complete(move || {
+                tx.send(inner.take().is_some()).unwrap();
+            });
+
+        promise.fail(Box::new(TestError));
+
+        assert_eq!(rx.recv().unwrap(), true);
+    }
 }
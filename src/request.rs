@@ -1,77 +1,274 @@
 use std::convert::AsRef;
-use std::collections::HashMap;
+use std::cmp;
 use bytes::{Buf, ByteBuf, MutByteBuf};
 use std::mem;
 use std::error::Error;
 use std::convert::From;
 use std::string;
+use std::str;
+use std::ascii::AsciiExt;
+use std::fmt;
+use uri::{TargetForm, Uri};
+use head::{HeaderSpan, RequestHead};
+use headers::HeaderMap;
+use body::BodyStream;
 
-#[derive(Debug)]
-struct HttpError {
-    message: String,
-    cause: Option<Box<Error>>
+// Heads with more fields than this are left to the state machine
+const ZERO_COPY_HEADERS: usize = 64;
+
+/// Reasons a request could not be parsed, each answered with the status from `status()`
+/// before the connection is closed.
+#[derive(Debug, PartialEq)]
+pub enum HttpError {
+    BadMethod(String),
+    MethodNotAllowed(String),
+    BadRequestLine,
+    InvalidLineEnding,
+    MalformedHeader(String),
+    HeaderTooLarge,
+    UriTooLong,
+    InvalidUtf8,
+    InvalidTarget(String),
+    InvalidPercentEncoding(String),
+    InvalidContentLength(String),
+    ConflictingContentLength(String),
+    ContentLengthWithTransferEncoding,
+    ChunkedNotFinal(String),
+    BodyNotAllowed(String),
+    WhitespaceInHeaderName(String),
+    InvalidChunk(String),
+    BodyTooLarge,
+    UnsupportedTransferEncoding(String),
+    UnsupportedVersion(String),
+    InvalidPreface,
+    TransferEncodingInHttp10
 }
 
 pub enum HttpResult {
     Http1Incomplete {buffer: MutByteBuf, request_builder: HttpRequestBuilder},
     Http1Request {buffer: ByteBuf, request: HttpRequest},
     Http2Upgrade {buffer: ByteBuf, request: HttpRequest},
+    /// The headers carried an `Expect` field and are complete, but nothing after them has
+    /// been read. Parsing resumes by passing `buffer` back to `request_builder`.
+    Http1Expect {buffer: ByteBuf, request_builder: HttpRequestBuilder},
 }
 
 impl HttpError {
-    fn new(message: String) -> HttpError {
-        HttpError {
-            message: message,
-            cause: None
+    pub fn status(&self) -> u16 {
+        match *self {
+            HttpError::MethodNotAllowed(_) => 405,
+            HttpError::BodyTooLarge => 413,
+            HttpError::UriTooLong => 414,
+            HttpError::HeaderTooLarge => 431,
+            HttpError::UnsupportedTransferEncoding(_) => 501,
+            HttpError::UnsupportedVersion(_) => 505,
+            _ => 400
         }
     }
+}
 
-    fn with_cause(message: String, err: Box<Error>) -> HttpError {
-        HttpError {
-            message: message,
-            cause: Some(err)
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HttpError::BadMethod(ref method) => write!(f, "Invalid method {:?}", method),
+            HttpError::MethodNotAllowed(ref method) => write!(f, "Method {} not allowed", method),
+            HttpError::MalformedHeader(ref line) => write!(f, "Malformed header {:?}", line),
+            HttpError::InvalidTarget(ref target) => write!(f, "Invalid request target {:?}", target),
+            HttpError::InvalidPercentEncoding(ref value) => write!(f, "Invalid percent-encoding in {:?}", value),
+            HttpError::InvalidContentLength(ref value) => write!(f, "Invalid Content-Length {:?}", value),
+            HttpError::ConflictingContentLength(ref values) => write!(f, "Conflicting Content-Length values {:?}", values),
+            HttpError::ChunkedNotFinal(ref value) => write!(f, "Transfer-Encoding {:?} does not end in chunked", value),
+            HttpError::BodyNotAllowed(ref method) => write!(f, "Method {} does not take a body", method),
+            HttpError::WhitespaceInHeaderName(ref name) => write!(f, "Whitespace around header name {:?}", name),
+            HttpError::InvalidChunk(ref reason) => write!(f, "Invalid chunk: {}", reason),
+            HttpError::UnsupportedTransferEncoding(ref value) => write!(f, "Unsupported Transfer-Encoding {}", value),
+            HttpError::UnsupportedVersion(ref version) => write!(f, "Unsupported version {}", version),
+            _ => f.write_str(self.description())
         }
     }
 }
 
-impl PartialEq for HttpError {
-    fn eq(&self, other: &HttpError) -> bool {
-        other.message == self.message
+impl Error for HttpError {
+    fn description(&self) -> &str {
+        match *self {
+            HttpError::BadMethod(_) => "Invalid method",
+            HttpError::MethodNotAllowed(_) => "Method not allowed",
+            HttpError::BadRequestLine => "Malformed request line",
+            HttpError::InvalidLineEnding => "Lines must end with CRLF",
+            HttpError::MalformedHeader(_) => "Malformed header",
+            HttpError::HeaderTooLarge => "Header fields too large",
+            HttpError::UriTooLong => "Request target too long",
+            HttpError::InvalidUtf8 => "Request is not valid UTF-8",
+            HttpError::InvalidTarget(_) => "Invalid request target",
+            HttpError::InvalidPercentEncoding(_) => "Invalid percent-encoding",
+            HttpError::InvalidContentLength(_) => "Invalid Content-Length",
+            HttpError::ConflictingContentLength(_) => "Conflicting Content-Length values",
+            HttpError::ContentLengthWithTransferEncoding => "Both Content-Length and Transfer-Encoding present",
+            HttpError::ChunkedNotFinal(_) => "Transfer-Encoding does not end in chunked",
+            HttpError::BodyNotAllowed(_) => "Method does not take a body",
+            HttpError::WhitespaceInHeaderName(_) => "Whitespace around header name",
+            HttpError::InvalidChunk(_) => "Invalid chunk",
+            HttpError::BodyTooLarge => "Body too large",
+            HttpError::UnsupportedTransferEncoding(_) => "Unsupported Transfer-Encoding",
+            HttpError::UnsupportedVersion(_) => "Unsupported version",
+            HttpError::InvalidPreface => "Invalid HTTP/2 connection preface",
+            HttpError::TransferEncodingInHttp10 => "Transfer-Encoding in an HTTP/1.0 request"
+        }
     }
 }
 
 impl From<string::FromUtf8Error> for HttpError {
-    fn from(err: string::FromUtf8Error) -> HttpError {
-        HttpError::with_cause(String::from("Error parsing string"), Box::new(err))
+    fn from(_: string::FromUtf8Error) -> HttpError {
+        HttpError::InvalidUtf8
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum HttpMethod {
+/// Request methods from RFC 9110 and RFC 5789, with any other token kept as an extension.
+///
+/// Method names are case-sensitive, so `get` is an extension method rather than `GET`.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum HttpMethod {
     GET,
+    HEAD,
     POST,
     PUT,
     DELETE,
+    CONNECT,
     OPTIONS,
-    HTTP2
+    TRACE,
+    PATCH,
+    HTTP2,
+    Extension(String)
+}
+
+fn is_known_coding(coding: &str) -> bool {
+    match coding {
+        "gzip" | "x-gzip" | "deflate" | "compress" | "x-compress" => true,
+        _ => false
+    }
+}
+
+pub fn is_control_char(character: u8) -> bool {
+    (character < b' ' && character != b'\t' && character != b'\r' && character != b'\n') || character == 0x7f
+}
+
+/// Joins obs-fold continuation lines with a single space, the replacement RFC 9112 allows.
+fn unfold(value: &str) -> String {
+    if !value.contains('\n') {
+        return String::from(value);
+    }
+
+    let lines: Vec<&str> = value.split('\n').map(|line| line.trim()).filter(|line| !line.is_empty()).collect();
+    lines.join(" ")
+}
+
+pub fn is_token_char(character: u8) -> bool {
+    match character {
+        b'a' ... b'z' | b'A' ... b'Z' | b'0' ... b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => false
+    }
 }
 
 impl HttpMethod {
-    fn parse(value :&str) -> Result<HttpMethod, HttpError> {
-        match value.to_uppercase().as_ref() {
+    pub fn parse(value :&str) -> Result<HttpMethod, HttpError> {
+        match value {
             "GET" => Ok(HttpMethod::GET),
+            "HEAD" => Ok(HttpMethod::HEAD),
             "POST" => Ok(HttpMethod::POST),
             "PUT" => Ok(HttpMethod::PUT),
             "DELETE" => Ok(HttpMethod::DELETE),
+            "CONNECT" => Ok(HttpMethod::CONNECT),
             "OPTIONS" => Ok(HttpMethod::OPTIONS),
+            "TRACE" => Ok(HttpMethod::TRACE),
+            "PATCH" => Ok(HttpMethod::PATCH),
             "PRI" => Ok(HttpMethod::HTTP2),
-            other => {
-                Err(HttpError::new(format!("Unrecognised method {}", other)))
-            }
+            _ if !value.is_empty() && value.bytes().all(is_token_char) => Ok(HttpMethod::Extension(String::from(value))),
+            _ => Err(HttpError::BadMethod(String::from(value)))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            HttpMethod::GET => "GET",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::DELETE => "DELETE",
+            HttpMethod::CONNECT => "CONNECT",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::TRACE => "TRACE",
+            HttpMethod::PATCH => "PATCH",
+            HttpMethod::HTTP2 => "PRI",
+            HttpMethod::Extension(ref method) => method
+        }
+    }
+
+    /// Whether request content has defined semantics for the method, strict parsing refuses
+    /// it otherwise. Extension methods are assumed to accept content since nothing is known
+    /// about them.
+    pub fn allows_body(&self) -> bool {
+        match *self {
+            HttpMethod::POST | HttpMethod::PUT | HttpMethod::PATCH | HttpMethod::OPTIONS => true,
+            HttpMethod::Extension(_) => true,
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HttpVersion {
+    Http09,
+    Http10,
+    Http11,
+    Http20
+}
+
+impl HttpVersion {
+    /// Parses the `HTTP/x.y` token of a request line. Later minor versions of HTTP/1
+    /// are treated as HTTP/1.1, the highest this server understands.
+    pub fn parse(value: &str) -> Result<HttpVersion, HttpError> {
+        let bytes = value.as_bytes();
+        if bytes.len() != 8 || &bytes[.. 5] != b"HTTP/" || bytes[6] != b'.' {
+            return Err(HttpError::BadRequestLine);
+        }
+
+        let (major, minor) = match ((bytes[5] as char).to_digit(10), (bytes[7] as char).to_digit(10)) {
+            (Some(major), Some(minor)) => (major, minor),
+            _ => return Err(HttpError::BadRequestLine)
+        };
+
+        match (major, minor) {
+            (0, 9) => Ok(HttpVersion::Http09),
+            (1, 0) => Ok(HttpVersion::Http10),
+            (1, _) => Ok(HttpVersion::Http11),
+            (2, 0) => Ok(HttpVersion::Http20),
+            _ => Err(HttpError::UnsupportedVersion(String::from(value)))
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HttpVersion::Http09 => "HTTP/0.9",
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+            HttpVersion::Http20 => "HTTP/2.0"
         }
     }
 }
 
+impl fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(PartialEq, Debug)]
 enum ParserStates {
 
@@ -86,134 +283,748 @@ enum ParserStates {
     HeadersNewLine{title: String},
     EndHeaders,
 
+    //Body parsing states
+    Body{remaining: usize},
+    ChunkedBody{state: ChunkedStates},
+
+    // Section complete states
+    Complete
+}
+
+#[derive(PartialEq, Debug)]
+enum ChunkedStates {
+
+    //Chunk size line states
+    Start,
+    Size{size: usize},
+    Extension{size: usize},
+    SizeNewLine{size: usize},
+
+    //Chunk data states
+    Data{remaining: usize},
+    DataEnd,
+    DataNewLine,
+
+    //Trailer parsing states
+    Trailer,
+
     // Section complete states
     Complete
 }
 
+/// Caps on how much of a request the parser accepts before giving up, so a client can't
+/// make the server buffer unbounded request lines, headers or bodies.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ParserLimits {
+    max_request_line: usize,
+    max_header_size: usize,
+    max_headers_size: usize,
+    max_headers: usize,
+    max_body_size: usize
+}
+
+impl ParserLimits {
+    pub fn new() -> ParserLimits {
+        ParserLimits {
+            max_request_line: 8192,
+            max_header_size: 8192,
+            max_headers_size: 65536,
+            max_headers: 100,
+            max_body_size: 16 * 1024 * 1024
+        }
+    }
+
+    /// Longest request line accepted, exceeding it is answered with `414 URI Too Long`.
+    pub fn max_request_line(mut self, length: usize) -> ParserLimits {
+        self.max_request_line = length;
+        self
+    }
+
+    /// Longest single header or trailer line, answered with `431` when exceeded.
+    pub fn max_header_size(mut self, length: usize) -> ParserLimits {
+        self.max_header_size = length;
+        self
+    }
+
+    /// Largest header block after the request line, answered with `431` when exceeded.
+    pub fn max_headers_size(mut self, length: usize) -> ParserLimits {
+        self.max_headers_size = length;
+        self
+    }
+
+    /// Most header fields in a request, trailers included, answered with `431` when exceeded.
+    pub fn max_headers(mut self, count: usize) -> ParserLimits {
+        self.max_headers = count;
+        self
+    }
+
+    /// Largest body accepted, answered with `413 Payload Too Large` when exceeded.
+    pub fn max_body_size(mut self, length: usize) -> ParserLimits {
+        self.max_body_size = length;
+        self
+    }
+
+    /// Checks a request line of `length` bytes so far, line ending included.
+    pub fn check_request_line(&self, length: usize) -> Result<(), HttpError> {
+        match length > self.max_request_line {
+            true => Err(HttpError::UriTooLong),
+            false => Ok(())
+        }
+    }
+
+    /// Checks a header line of `length` bytes and a header block of `total` bytes so far.
+    pub fn check_header_line(&self, length: usize, total: usize) -> Result<(), HttpError> {
+        match length > self.max_header_size || total > self.max_headers_size {
+            true => Err(HttpError::HeaderTooLarge),
+            false => Ok(())
+        }
+    }
+
+    pub fn check_header_count(&self, count: usize) -> Result<(), HttpError> {
+        match count > self.max_headers {
+            true => Err(HttpError::HeaderTooLarge),
+            false => Ok(())
+        }
+    }
+}
+
+impl Default for ParserLimits {
+    fn default() -> ParserLimits {
+        ParserLimits::new()
+    }
+}
+
+/// How forgiving the parser is about malformed framing.
+///
+/// `Strict` follows RFC 9112 to the letter: every line must end in CRLF, header and trailer
+/// names must be tokens, values may not hold control characters and obs-fold continuation
+/// lines are refused, as are bodies on methods such as GET that give them no meaning.
+/// `Lenient` also accepts bare LF line endings and names with characters outside the token
+/// set, and unfolds continuation lines into a single space. Whitespace around a name is
+/// refused in both modes, since servers disagree on what such a field means.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ParserMode {
+    Strict,
+    Lenient
+}
+
 #[derive(Debug)]
 pub struct HttpRequestBuilder{
     method: Result<HttpMethod, HttpError>,
-    path: Option<String>,
-    version: Option<String>,
-    headers: HashMap<String, Vec<String>>,
+    uri: Option<Uri>,
+    version: Option<HttpVersion>,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    body_length: usize,
+    trailers: HeaderMap,
     state: ParserStates,
-    temporary_data: Vec<u8>
+    temporary_data: Vec<u8>,
+    limits: ParserLimits,
+    mode: ParserMode,
+    zero_copy: bool,
+    last_byte: u8,
+    line_length: usize,
+    headers_size: usize
 }
 
-struct HttpRequest {
+pub struct HttpRequest {
     method: HttpMethod,
-    path: String,
-    headers: HashMap<String, Vec<String>>
+    uri: Uri,
+    version: HttpVersion,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    trailers: HeaderMap,
+    stream: Option<BodyStream>
 }
 
 impl  HttpRequestBuilder {
-    pub fn new() -> HttpRequestBuilder {
+    pub fn new(limits: ParserLimits) -> HttpRequestBuilder {
         HttpRequestBuilder {
-            method : Err(HttpError::new(String::from("Method not declared"))),
-            path: None,
+            method : Err(HttpError::BadRequestLine),
+            uri: None,
             version: None,
-            headers: HashMap::new(),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            body_length: 0,
+            trailers: HeaderMap::new(),
             state: ParserStates::Verb,
-            temporary_data: Vec::new()
+            temporary_data: Vec::new(),
+            limits: limits,
+            mode: ParserMode::Lenient,
+            zero_copy: false,
+            last_byte: 0,
+            line_length: 0,
+            headers_size: 0
         }
     }
 
+    pub fn mode(mut self, mode: ParserMode) -> HttpRequestBuilder {
+        self.mode = mode;
+        self
+    }
+
+    /// Parses a head that arrives whole in one read with `RequestHead`, straight from the
+    /// buffer, instead of copying it a byte at a time. Only the fields that are kept get
+    /// allocated. Heads that arrive in pieces still go through the state machine.
+    pub fn zero_copy(mut self, zero_copy: bool) -> HttpRequestBuilder {
+        self.zero_copy = zero_copy;
+        self
+    }
+
     pub fn build(self) -> Result<HttpRequest, HttpError> {
         let method = try!(self.method);
-        let path = try!(self.path.ok_or(HttpError::new(format!("Path not parsed"))));
+        let uri = try!(self.uri.ok_or(HttpError::BadRequestLine));
+        let version = try!(self.version.ok_or(HttpError::BadRequestLine));
 
         Ok(HttpRequest {
             method: method,
-            path: path,
-            headers: self.headers
+            uri: uri,
+            version: version,
+            headers: self.headers,
+            body: self.body,
+            trailers: self.trailers,
+            stream: None
         })
     }
 
-    fn read_value(&mut self, buffer: &mut ByteBuf, length: usize) -> Result<String, HttpError> {
-        let (mut data, start) = match self.temporary_data.len() {
-            0 => (Vec::with_capacity(length), 0),
+    /// Whether any bytes of a request have been consumed yet.
+    pub fn is_started(&self) -> bool {
+        self.state != ParserStates::Verb || !self.temporary_data.is_empty()
+    }
+
+    fn is_reading_headers(&self) -> bool {
+        match self.state {
+            ParserStates::Body{..} | ParserStates::ChunkedBody{..} | ParserStates::Complete => false,
+            _ => true
+        }
+    }
+
+    /// The value of the `Expect` header once the headers are complete.
+    pub fn expectation(&self) -> Option<&str> {
+        match self.is_reading_headers() {
+            true => None,
+            false => self.headers.get("Expect")
+        }
+    }
+
+    /// The request as known so far, without its body, so a handler can judge it before
+    /// the body is read. Only available once the headers are complete.
+    pub fn preview(&self) -> Option<HttpRequest> {
+        if self.is_reading_headers() {
+            return None;
+        }
+
+        match (&self.method, &self.uri, self.version) {
+            (&Ok(ref method), &Some(ref uri), Some(version)) => Some(HttpRequest {
+                method: method.clone(),
+                uri: uri.clone(),
+                version: version,
+                headers: self.headers.clone(),
+                body: Vec::new(),
+                trailers: HeaderMap::new(),
+                stream: None
+            }),
+            _ => None
+        }
+    }
+
+    pub fn is_reading_body(&self) -> bool {
+        match self.state {
+            ParserStates::Body{..} | ParserStates::ChunkedBody{..} => true,
+            _ => false
+        }
+    }
+
+    /// Hands over the body read so far, so it can be streamed while the rest arrives.
+    /// Limits still count the whole body.
+    pub fn take_body(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.body, Vec::new())
+    }
+
+    /// Works out how the body is framed, refusing any combination of framing headers that
+    /// another server on the path could read differently, since that disagreement is what
+    /// request smuggling exploits.
+    fn body_state(&self) -> Result<ParserStates, HttpError> {
+        // HTTP/1.0 has no chunked coding, so anything going by the version frames the body
+        // differently. Lenient mode decodes it but closes the connection afterwards
+        if self.mode == ParserMode::Strict && self.version == Some(HttpVersion::Http10) && self.headers.contains("Transfer-Encoding") {
+            return Err(HttpError::TransferEncodingInHttp10);
+        }
+
+        let state = try!(self.framing());
+
+        // A body the method gives no meaning to is a common way to smuggle a second request
+        // past servers that ignore it
+        if self.mode == ParserMode::Strict && state != ParserStates::Complete {
+            if let Ok(ref method) = self.method {
+                if !method.allows_body() {
+                    return Err(HttpError::BodyNotAllowed(String::from(method.as_str())));
+                }
+            }
+        }
+
+        Ok(state)
+    }
+
+    fn framing(&self) -> Result<ParserStates, HttpError> {
+        let transfer_encoding = self.headers.get_joined("Transfer-Encoding");
+
+        if transfer_encoding.is_some() && self.headers.contains("Content-Length") {
+            return Err(HttpError::ContentLengthWithTransferEncoding);
+        }
+
+        match transfer_encoding {
+            Some(value) => {
+                let codings: Vec<String> = value.split(',').map(|coding| coding.trim().to_lowercase()).collect();
+                let last_coding = &codings[codings.len() - 1];
+                let other_codings = &codings[.. codings.len() - 1];
+
+                if last_coding != "chunked" || other_codings.iter().any(|coding| coding == "chunked") {
+                    return Err(HttpError::ChunkedNotFinal(value.clone()));
+                }
+
+                match other_codings.iter().find(|coding| !is_known_coding(coding)) {
+                    Some(_) => Err(HttpError::UnsupportedTransferEncoding(value.clone())),
+                    None => Ok(ParserStates::ChunkedBody{state: ChunkedStates::Start})
+                }
+            },
+            None => match try!(self.content_length()) {
+                0 => Ok(ParserStates::Complete),
+                length if length > self.limits.max_body_size => Err(HttpError::BodyTooLarge),
+                length => Ok(ParserStates::Body{remaining: length})
+            }
+        }
+    }
+
+    /// Strict mode only accepts CR immediately followed by LF, lenient mode takes either alone.
+    /// The HTTP/2 connection preface is a fixed sequence of bytes, so it is always strict.
+    fn check_line_ending(&mut self, character: u8) -> Result<(), HttpError> {
+        let previous = mem::replace(&mut self.last_byte, character);
+        if self.mode == ParserMode::Lenient && self.method != Ok(HttpMethod::HTTP2) {
+            return Ok(());
+        }
+
+        match (previous, character) {
+            (b'\r', b'\n') => Ok(()),
+            (b'\r', _) | (_, b'\n') => Err(HttpError::InvalidLineEnding),
+            _ => Ok(())
+        }
+    }
+
+    /// Counts a byte of the request head against the line and header block limits.
+    fn count_head_byte(&mut self) -> Result<(), HttpError> {
+        self.line_length += 1;
+
+        match self.state {
+            ParserStates::Verb | ParserStates::Path | ParserStates::Version =>
+                self.limits.check_request_line(self.line_length),
             _ => {
-                println!("Using leftover data");
-                let mut temp = mem::replace(&mut self.temporary_data, Vec::new());
-                let start = temp.len();
-                temp.reserve(length);
-                (temp, start)
+                self.headers_size += 1;
+                self.limits.check_header_line(self.line_length, self.headers_size)
             }
+        }
+    }
+
+    fn add_header(&mut self, name: &str, value: &str) -> Result<(), HttpError> {
+        try!(self.limits.check_header_count(self.headers.len() + self.trailers.len() + 1));
+        self.headers.append(name, value);
+        Ok(())
+    }
+
+    /// Repeated Content-Length fields, or one holding a list, are only accepted when every
+    /// value is the same.
+    fn content_length(&self) -> Result<usize, HttpError> {
+        let values = match self.headers.get_joined("Content-Length") {
+            Some(values) => values,
+            None => return Ok(0)
         };
 
-        unsafe{ data.set_len(start + length); }
+        let mut length = None;
+        for value in values.split(',').map(|value| value.trim()) {
+            if value.is_empty() || !value.bytes().all(|byte| byte >= b'0' && byte <= b'9') {
+                return Err(HttpError::InvalidContentLength(String::from(value)));
+            }
+
+            // All digits, so the only way to fail is overflowing usize
+            let parsed = try!(value.parse::<usize>().map_err(|_| HttpError::BodyTooLarge));
+            if length.map_or(false, |length| length != parsed) {
+                return Err(HttpError::ConflictingContentLength(values.clone()));
+            }
+
+            length = Some(parsed);
+        }
+
+        Ok(length.unwrap_or(0))
+    }
+
+    fn read_body_data(&mut self, buffer: &mut ByteBuf, remaining: usize) -> usize {
+        let length = cmp::min(remaining, buffer.remaining());
+        self.body.extend(buffer.bytes()[.. length].iter().cloned());
+        self.body_length += length;
+        buffer.advance(length);
+
+        remaining - length
+    }
+
+    fn read_body(&mut self, buffer: &mut ByteBuf, remaining: usize) -> ParserStates {
+        match self.read_body_data(buffer, remaining) {
+            0 => ParserStates::Complete,
+            remaining => ParserStates::Body{remaining: remaining}
+        }
+    }
+
+    fn read_chunked_body(&mut self, buffer: &mut ByteBuf, mut state: ChunkedStates) -> Result<ParserStates, HttpError> {
+        loop {
+            if let ChunkedStates::Data{remaining} = state {
+                state = match self.read_body_data(buffer, remaining) {
+                    0 => ChunkedStates::DataEnd,
+                    remaining => return Ok(ParserStates::ChunkedBody{state: ChunkedStates::Data{remaining: remaining}})
+                };
+            }
+
+            if state == ChunkedStates::Complete {
+                return Ok(ParserStates::Complete);
+            }
+
+            let character = match buffer.read_byte() {
+                Some(character) => character,
+                None => return Ok(ParserStates::ChunkedBody{state: state})
+            };
+            try!(self.check_line_ending(character));
+
+            state = match (state, character as char) {
+                (ChunkedStates::Start, digit) => match digit.to_digit(16) {
+                    Some(value) => ChunkedStates::Size{size: value as usize},
+                    None => return Err(HttpError::InvalidChunk(format!("size character {:?}", digit)))
+                },
+                (ChunkedStates::Size{size}, ';') | (ChunkedStates::Size{size}, ' ') | (ChunkedStates::Size{size}, '\t') => {
+                    self.line_length = 1;
+                    ChunkedStates::Extension{size: size}
+                },
+                (ChunkedStates::Size{size}, '\r') | (ChunkedStates::Extension{size}, '\r') =>
+                    ChunkedStates::SizeNewLine{size: size},
+                (ChunkedStates::Size{size}, '\n') | (ChunkedStates::Extension{size}, '\n') | (ChunkedStates::SizeNewLine{size}, '\n') =>
+                    match size {
+                        0 => ChunkedStates::Trailer,
+                        size if size > self.limits.max_body_size - cmp::min(self.body_length, self.limits.max_body_size) =>
+                            return Err(HttpError::BodyTooLarge),
+                        size => ChunkedStates::Data{remaining: size}
+                    },
+                (ChunkedStates::Size{size}, digit) => {
+                    let value = try!(digit.to_digit(16)
+                        .ok_or(HttpError::InvalidChunk(format!("size character {:?}", digit))));
+                    let size = try!(size.checked_mul(16).and_then(|size| size.checked_add(value as usize))
+                        .ok_or(HttpError::BodyTooLarge));
+                    ChunkedStates::Size{size: size}
+                },
+                (ChunkedStates::Extension{size}, _) => {
+                    // Extensions are ignored but still count against the header limit
+                    self.line_length += 1;
+                    if self.line_length > self.limits.max_header_size {
+                        return Err(HttpError::HeaderTooLarge);
+                    }
+
+                    ChunkedStates::Extension{size: size}
+                },
+                (ChunkedStates::DataEnd, '\r') => ChunkedStates::DataNewLine,
+                (ChunkedStates::DataEnd, '\n') | (ChunkedStates::DataNewLine, '\n') => ChunkedStates::Start,
+                (ChunkedStates::Trailer, '\n') => try!(self.read_trailer()),
+                (ChunkedStates::Trailer, _) => {
+                    if self.temporary_data.len() >= self.limits.max_header_size {
+                        return Err(HttpError::HeaderTooLarge);
+                    }
+
+                    self.temporary_data.push(character);
+                    ChunkedStates::Trailer
+                },
+                (state, character) =>
+                    return Err(HttpError::InvalidChunk(format!("unexpected {:?} in {:?}", character, state)))
+            };
+        }
+    }
+
+    fn read_trailer(&mut self) -> Result<ChunkedStates, HttpError> {
+        let line = try!(String::from_utf8(mem::replace(&mut self.temporary_data, Vec::new())));
+        let line = line.trim_right_matches('\r');
+
+        if line.trim().is_empty() {
+            return Ok(ChunkedStates::Complete);
+        }
+
+        match line.find(':') {
+            Some(index) => {
+                let name = &line[.. index];
+                if name.trim() != name {
+                    return Err(HttpError::WhitespaceInHeaderName(String::from(name)));
+                }
+
+                // Trailer fields are held to the same rules as header fields
+                let strict = self.mode == ParserMode::Strict;
+                if name.is_empty() || (strict && !name.bytes().all(is_token_char)) {
+                    return Err(HttpError::MalformedHeader(String::from(name)));
+                }
+
+                let value = line[index + 1 ..].trim();
+                if strict && value.bytes().any(is_control_char) {
+                    return Err(HttpError::MalformedHeader(format!("control character in {}", name)));
+                }
+
+                // Framing can't be changed once the body has been read
+                if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+                    return Err(HttpError::MalformedHeader(String::from(line)));
+                }
+
+                try!(self.limits.check_header_count(self.headers.len() + self.trailers.len() + 1));
+                self.trailers.append(name, value);
+                Ok(ChunkedStates::Trailer)
+            },
+            None => Err(HttpError::MalformedHeader(String::from(line)))
+        }
+    }
+
+    fn read_value(&mut self, buffer: &mut ByteBuf, length: usize) -> Result<String, HttpError> {
+        self.read_raw_value(buffer, length).map(|value| String::from(value.trim()))
+    }
+
+    fn read_raw_value(&mut self, buffer: &mut ByteBuf, length: usize) -> Result<String, HttpError> {
+        let mut data = mem::replace(&mut self.temporary_data, Vec::new());
+
         buffer.reset();
-        buffer.read_slice(&mut data[start .. (start + length)]);
-        buffer.advance(1);
+        let length = cmp::min(length, buffer.remaining());
+        data.extend(buffer.bytes()[.. length].iter().cloned());
+        buffer.advance(length + 1);
         buffer.mark();
-        match String::from_utf8(data) {
-            Ok(data) => Ok(String::from(data.trim())),
-            Err(err) => Err(HttpError::from(err))
+
+        String::from_utf8(data).map_err(HttpError::from)
+    }
+
+    /// Only HTTP/1.0 and HTTP/1.1 requests are served, HTTP/2.0 may only appear in the
+    /// `PRI * HTTP/2.0` line that starts the HTTP/2 connection preface.
+    fn check_version(&self, version: HttpVersion) -> Result<(), HttpError> {
+        let preface = self.method == Ok(HttpMethod::HTTP2);
+
+        if preface && (version != HttpVersion::Http20 || self.uri.as_ref().map(Uri::form) != Some(TargetForm::Asterisk)) {
+            return Err(HttpError::MethodNotAllowed(String::from("PRI")));
+        }
+
+        match version {
+            HttpVersion::Http10 | HttpVersion::Http11 => Ok(()),
+            HttpVersion::Http20 if preface => Ok(()),
+            _ => Err(HttpError::UnsupportedVersion(String::from(version.as_str())))
         }
     }
 
     pub fn parse(mut self, mut buffer: ByteBuf) -> Result<HttpResult, HttpError> {
+        if self.is_reading_headers() {
+            let parsed = match self.zero_copy && !self.is_started() {
+                true => try!(self.parse_head(&mut buffer)),
+                false => false
+            };
+
+            if !parsed && !try!(self.parse_headers(&mut buffer)) {
+                return Ok(HttpResult::Http1Incomplete{buffer: buffer.flip(), request_builder: self});
+            }
+
+            // Stop before the body so the expectation can be answered first
+            if self.headers.contains("Expect") {
+                return Ok(HttpResult::Http1Expect{buffer: buffer, request_builder: self});
+            }
+        }
+
+        self.state = match mem::replace(&mut self.state, ParserStates::Complete) {
+            ParserStates::Body{remaining} => self.read_body(&mut buffer, remaining),
+            ParserStates::ChunkedBody{state} => try!(self.read_chunked_body(&mut buffer, state)),
+            state => state
+        };
+
+        if self.state != ParserStates::Complete {
+            return Ok(HttpResult::Http1Incomplete{buffer: buffer.flip(), request_builder: self});
+        }
+
+        let request = try!(self.build());
+        match request.method {
+            // The preface has nothing between the request line and the blank line
+            HttpMethod::HTTP2 if !request.headers.is_empty() => Err(HttpError::InvalidPreface),
+            HttpMethod::HTTP2 => Ok(HttpResult::Http2Upgrade{buffer: buffer, request: request}),
+            _ => Ok(HttpResult::Http1Request{buffer: buffer, request: request})
+        }
+    }
+
+    /// Takes a whole head from the buffer without copying it first. Anything `RequestHead`
+    /// refuses or can't finish is left to `parse_headers` by returning false, so requests
+    /// are accepted and refused with the same errors either way.
+    fn parse_head(&mut self, buffer: &mut ByteBuf) -> Result<bool, HttpError> {
+        if buffer.remaining() == 0 {
+            return Ok(false);
+        }
+
+        // The state machine counts the first byte of every header line twice and the blank
+        // line against the last field, so the head is held to slightly tighter limits
+        let limits = ParserLimits {
+            max_header_size: self.limits.max_header_size.saturating_sub(2),
+            max_headers_size: self.limits.max_headers_size.saturating_sub(ZERO_COPY_HEADERS),
+            ..self.limits
+        };
+        let mut spans = [HeaderSpan::default(); ZERO_COPY_HEADERS];
+
+        let length = {
+            let head = match RequestHead::parse(buffer.bytes(), &mut spans, &limits, self.mode) {
+                Ok(Some(head)) => head,
+                _ => return Ok(false)
+            };
+
+            // The preface is always held to CRLF, values the builder would trim or that
+            // aren't UTF-8 are also left to it
+            if head.method() == HttpMethod::HTTP2.as_str() || head.path().trim() != head.path()
+                    || head.headers().any(|(_, value)| str::from_utf8(value).is_err()) {
+                return Ok(false);
+            }
+
+            let method = try!(HttpMethod::parse(head.method()));
+            self.uri = Some(try!(Uri::parse(head.path(), &method)));
+            self.method = Ok(method);
+            try!(self.check_version(head.version()));
+            self.version = Some(head.version());
+
+            for (name, value) in head.headers() {
+                try!(self.add_header(name, str::from_utf8(value).unwrap_or("").trim()));
+            }
+
+            head.len()
+        };
+
+        buffer.advance(length);
+        self.last_byte = b'\n';
+        self.temporary_data.clear();
+        self.state = try!(self.body_state());
+        Ok(true)
+    }
+
+    fn parse_headers(&mut self, buffer: &mut ByteBuf) -> Result<bool, HttpError> {
         let mut state_length = 0;
         buffer.mark();
 
         while let Some(character) = buffer.read_byte() {
+            try!(self.count_head_byte());
+            try!(self.check_line_ending(character));
+
+            if self.mode == ParserMode::Strict && is_control_char(character) {
+                if let ParserStates::HeaderContent{ref title} = self.state {
+                    return Err(HttpError::MalformedHeader(format!("control character in {}", title)));
+                }
+            }
+
             let state = mem::replace(&mut self.state, ParserStates::Complete);
-            println!("({:?}, {:?})",state, character as char);
+            trace!("({:?}, {:?})",state, character as char);
             let (next_state, next_length) = match (state, character as char) {
                 (ParserStates::Verb, ' ') => {
-                    let verb = try!(self.read_value(&mut buffer, state_length));
-                    self.method = HttpMethod::parse(& verb);
+                    let verb = try!(self.read_value(buffer, state_length));
+                    self.method = Ok(try!(HttpMethod::parse(& verb)));
                     (ParserStates::Path, 0)
                 },
                 (ParserStates::Path, ' ') => {
-                    self.path = Some(try!(self.read_value(&mut buffer, state_length)));
+                    let path = try!(self.read_value(buffer, state_length));
+                    if path.is_empty() {
+                        return Err(HttpError::BadRequestLine);
+                    }
+
+                    let uri = match self.method {
+                        Ok(ref method) => try!(Uri::parse(&path, method)),
+                        Err(_) => return Err(HttpError::BadRequestLine)
+                    };
+
+                    self.uri = Some(uri);
                     (ParserStates::Version, 0)
                 },
+                (ParserStates::Verb, '\n') => {
+                    // Empty lines before the request line are ignored, such as a stray CRLF after a body
+                    if !try!(self.read_value(buffer, state_length)).is_empty() {
+                        return Err(HttpError::BadRequestLine);
+                    }
+
+                    (ParserStates::Verb, 0)
+                },
+                (ParserStates::Path, '\n') => return Err(HttpError::BadRequestLine),
                 (ParserStates::Version, '\n') => {
-                    self.version = Some(try!(self.read_value(&mut buffer, state_length)));
+                    let version = try!(HttpVersion::parse(&try!(self.read_value(buffer, state_length))));
+                    try!(self.check_version(version));
+                    self.version = Some(version);
                     (ParserStates::HeaderTitle, 0)
                 },
-                (ParserStates::HeaderTitle, '\n') => (ParserStates::Complete, 1),
+                (ParserStates::HeaderTitle, '\n') => {
+                    let line = try!(self.read_value(buffer, state_length));
+                    if !line.is_empty() {
+                        return Err(HttpError::MalformedHeader(line));
+                    }
+
+                    (ParserStates::Complete, 0)
+                },
                 (ParserStates::HeaderTitle, ':') => {
-                    let header_title = try!(self.read_value(&mut buffer, state_length)).to_uppercase();
+                    let raw_title = try!(self.read_raw_value(buffer, state_length));
+                    let header_title = String::from(raw_title.trim());
+
+                    // "Transfer-Encoding : chunked" is ignored by some servers and honoured by others
+                    if !header_title.is_empty() && header_title != raw_title {
+                        return Err(HttpError::WhitespaceInHeaderName(raw_title));
+                    }
+
+                    let strict_violation = self.mode == ParserMode::Strict && !raw_title.bytes().all(is_token_char);
+                    if header_title.is_empty() || strict_violation {
+                        return Err(HttpError::MalformedHeader(raw_title));
+                    }
+
                     (ParserStates::HeaderContent{title: header_title}, 0)
                 },
                 (ParserStates::HeaderContent{title}, '\n') =>
                     (ParserStates::HeadersNewLine{title: title}, state_length + 1),
                 (ParserStates::HeadersNewLine{title}, ' ') | (ParserStates::HeadersNewLine{title}, '\t') => {
-                    (ParserStates::HeaderContent{title: title}, state_length + 2)
+                    if self.mode == ParserMode::Strict {
+                        return Err(HttpError::MalformedHeader(format!("obsolete line folding in {}", title)));
+                    }
+
+                    (ParserStates::HeaderContent{title: title}, state_length + 1)
                 },
                 (ParserStates::HeadersNewLine{title}, '\n') => {
-                    let header_value = vec![try!(self.read_value(&mut buffer, state_length))];
-                    self.headers.insert(title, header_value);
+                    let header_value = unfold(&try!(self.read_value(buffer, state_length)));
+                    try!(self.add_header(&title, &header_value));
                     (ParserStates::Complete, 0)
                 },
                 (ParserStates::HeadersNewLine{title}, '\r') => {
-                    let header_value = vec![try!(self.read_value(&mut buffer, state_length))];
-                    self.headers.insert(title, header_value);
+                    let header_value = unfold(&try!(self.read_value(buffer, state_length)));
+                    try!(self.add_header(&title, &header_value));
                     (ParserStates::EndHeaders, 0)
                 },
                 (ParserStates::HeadersNewLine{title}, _) => {
-                    let header_value = vec![try!(self.read_value(&mut buffer, state_length - 1))];
-                    self.headers.insert(title, header_value);
+                    // A line that ended with the previous read is all kept over, and the
+                    // byte just read is left to start the next line
+                    let header_value = match state_length {
+                        0 => {
+                            buffer.reset();
+                            buffer.mark();
+                            let data = mem::replace(&mut self.temporary_data, Vec::new());
+                            unfold(try!(String::from_utf8(data)).trim())
+                        },
+                        length => unfold(&try!(self.read_value(buffer, length - 1)))
+                    };
+                    try!(self.add_header(&title, &header_value));
                     (ParserStates::HeaderTitle, 0)
                 },
                 (ParserStates::EndHeaders, '\n') => (ParserStates::Complete, 0),
-                (ParserStates::EndHeaders, character) => panic!("Malformed headers {:?}", character),
+                (ParserStates::EndHeaders, character) =>
+                    return Err(HttpError::MalformedHeader(format!("unexpected {:?} after headers", character))),
                 (state, _) => (state, state_length + 1)
             };
 
             if next_state == ParserStates::Complete {
-                match self.build() {
-                    Ok(request) =>
-                        match request.method {
-                            HttpMethod::HTTP2 => return Ok(HttpResult::Http2Upgrade{buffer: buffer, request: request}),
-                            _ => return Ok(HttpResult::Http1Request{buffer: buffer, request: request})
-                        },
+                self.temporary_data.clear();
+                self.state = try!(self.body_state());
+                return Ok(true);
+            }
 
-                    Err(err) =>
-                        return Err(err)
-                }
+            // Each header line is measured on its own, as is the request line after skipped empty lines
+            match next_state {
+                ParserStates::HeaderTitle | ParserStates::Verb => self.line_length = 0,
+                _ => ()
             }
 
             mem::replace(&mut self.state, next_state);
@@ -221,41 +1032,114 @@ impl  HttpRequestBuilder {
         }
 
         if state_length>0{
-            println!("Storing {} leftover bytes", state_length);
-            let mut temporary_data = &mut self.temporary_data;
-            temporary_data.reserve(state_length);
-
-            let current_length = temporary_data.len();
-            unsafe{temporary_data.set_len(current_length + state_length);}
+            trace!("Storing {} leftover bytes", state_length);
             buffer.reset();
-            buffer.read_slice(&mut temporary_data);
+            let length = cmp::min(state_length, buffer.remaining());
+            self.temporary_data.extend(buffer.bytes()[.. length].iter().cloned());
         }
 
-        Ok(HttpResult::Http1Incomplete{buffer: buffer.flip(), request_builder: self})
+        Ok(false)
     }
 }
 
 impl HttpRequest {
+    pub fn method(&self) -> &HttpMethod {
+        &self.method
+    }
 
-}
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
 
-#[cfg(test)]
+    /// The raw path of the request target, without the query string.
+    pub fn path(&self) -> &str {
+        self.uri.path()
+    }
+
+    pub fn version(&self) -> HttpVersion {
+        self.version
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Whether the client expects the connection to stay open after this request.
+    ///
+    /// An explicit `Connection` header wins, otherwise HTTP/1.1 defaults to persistent
+    /// connections and earlier versions default to closing. An HTTP/1.0 request with a
+    /// `Transfer-Encoding` never keeps the connection, since its framing can't be trusted.
+    pub fn keep_alive(&self) -> bool {
+        if self.version == HttpVersion::Http10 && self.headers.contains("Transfer-Encoding") {
+            return false;
+        }
+
+        let mut keep_alive = self.version == HttpVersion::Http11;
+        if let Some(connection) = self.headers.get_joined("Connection") {
+            // Every option counts, a close anywhere wins over keep-alive
+            for option in connection.split(',') {
+                match option.trim().to_lowercase().as_ref() {
+                    "close" => return false,
+                    "keep-alive" => keep_alive = true,
+                    _ => ()
+                }
+            }
+        }
+
+        keep_alive
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Moves the body out of the request, leaving it empty.
+    pub fn take_body(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.body, Vec::new())
+    }
+
+    /// The body of a request the handler chose to stream, in which case `body()` is empty.
+    /// See `Handler::stream_body`.
+    pub fn take_body_stream(&mut self) -> Option<BodyStream> {
+        self.stream.take()
+    }
+
+    pub fn with_body_stream(mut self, stream: BodyStream) -> HttpRequest {
+        self.stream = Some(stream);
+        self
+    }
+
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+}
+
+#[cfg(test)]
 mod tests {
-    use bytes::ByteBuf;
+    use bytes::{Buf, ByteBuf};
     use super::HttpRequestBuilder;
+    use super::HttpRequest;
     use super::HttpMethod;
     use super::HttpResult;
+    use super::HttpError;
+    use super::HttpVersion;
+    use super::ParserLimits;
+    use super::ParserMode;
 
     #[test]
     fn test_http_request_builder() {
-        let buffer = ByteBuf::from_slice("GET / HTTP\n".as_bytes());
-        let request_builder = HttpRequestBuilder::new();
+        let buffer = ByteBuf::from_slice("GET / HTTP/1.1\n".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
 
         match request_builder.parse(buffer) {
             Ok(HttpResult::Http1Incomplete{request_builder, ..}) => {
                 assert_eq!(Ok(HttpMethod::GET), request_builder.method);
-                assert_eq!("/", request_builder.path.unwrap());
-                assert_eq!("HTTP", request_builder.version.unwrap());
+                assert_eq!("/", request_builder.uri.unwrap().path());
+                assert_eq!(HttpVersion::Http11, request_builder.version.unwrap());
             },
             _ => panic!("Expected Http1Incomplete")
         }
@@ -263,13 +1147,13 @@ mod tests {
 
     #[test]
     fn test_http_request_builder_post() {
-        let buffer = ByteBuf::from_slice("POST / HTTP\n".as_bytes());
-        let request_builder = HttpRequestBuilder::new();
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\n".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
         match request_builder.parse(buffer) {
             Ok(HttpResult::Http1Incomplete{request_builder, ..}) => {
                 assert_eq!(Ok(HttpMethod::POST), request_builder.method);
-                assert_eq!("/", request_builder.path.unwrap());
-                assert_eq!("HTTP", request_builder.version.unwrap());
+                assert_eq!("/", request_builder.uri.unwrap().path());
+                assert_eq!(HttpVersion::Http11, request_builder.version.unwrap());
             }
             _ => panic!("Expected Http1Incomplete")
         }
@@ -279,15 +1163,15 @@ mod tests {
     fn test_http_request_builder_complete_state() {
         let mut buffer = ByteBuf::mut_with_capacity(2048);
         buffer.write_slice("GET ".as_bytes());
-        let request_builder = HttpRequestBuilder::new();
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
         match request_builder.parse(buffer.flip()) {
             Ok(HttpResult::Http1Incomplete{mut buffer, request_builder}) => {
-                buffer.write_slice("/ HTTP\n".as_bytes());
+                buffer.write_slice("/ HTTP/1.1\n".as_bytes());
                 match request_builder.parse(buffer.flip()) {
                     Ok(HttpResult::Http1Incomplete{ request_builder, .. }) => {
                         assert_eq!(Ok(HttpMethod::GET), request_builder.method);
-                        assert_eq!("/", request_builder.path.unwrap());
-                        assert_eq!("HTTP", request_builder.version.unwrap());
+                        assert_eq!("/", request_builder.uri.unwrap().path());
+                        assert_eq!(HttpVersion::Http11, request_builder.version.unwrap());
                     }
 
                     _ => panic!("Expected Http1Incomplete")
@@ -302,7 +1186,7 @@ mod tests {
     fn test_http_request_builder_incomplete_state() {
         let mut buffer = ByteBuf::mut_with_capacity(2048);
         buffer.write_slice("GET".as_bytes());
-        let request_builder = HttpRequestBuilder::new();
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
         match request_builder.parse(buffer.flip()) {
             Ok(HttpResult::Http1Incomplete{mut buffer, request_builder}) => {
 
@@ -310,13 +1194,13 @@ mod tests {
                 match request_builder.parse(buffer.flip()) {
                     Ok(HttpResult::Http1Incomplete{mut buffer, request_builder}) => {
 
-                        buffer.write_slice("TTP\r\n".as_bytes());
+                        buffer.write_slice("TTP/1.1\r\n".as_bytes());
                         match request_builder.parse(buffer.flip()) {
                             Ok(HttpResult::Http1Incomplete{request_builder, ..}) => {
 
                                 assert_eq!(Ok(HttpMethod::GET), request_builder.method);
-                                assert_eq!("/", request_builder.path.unwrap());
-                                assert_eq!("HTTP", request_builder.version.unwrap());
+                                assert_eq!("/", request_builder.uri.unwrap().path());
+                                assert_eq!(HttpVersion::Http11, request_builder.version.unwrap());
                             }
 
                             _ => panic!("Expected Http1Incomplete")
@@ -333,32 +1217,32 @@ mod tests {
 
     #[test]
     fn test_http_request_builder_header() {
-        let buffer = ByteBuf::from_slice("GET / HTTP 1.1\r\nContent-Type:   application/json\n\n".as_bytes());
-        let request_builder = HttpRequestBuilder::new();
+        let buffer = ByteBuf::from_slice("GET / HTTP/1.1\r\nContent-Type:   application/json\n\n".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
         match request_builder.parse(buffer) {
-            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.headers["CONTENT-TYPE"], vec!["application/json"]),
+            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.headers.get_all("Content-Type"), vec!["application/json"]),
             _ => panic!("Expected Http1Request")
         }
     }
 
     #[test]
     fn test_http_request_builder_header_return() {
-        let buffer = ByteBuf::from_slice("GET / HTTP 1.1\nContent-Type:   application/json\n\r\n".as_bytes());
-        let request_builder = HttpRequestBuilder::new();
+        let buffer = ByteBuf::from_slice("GET / HTTP/1.1\nContent-Type:   application/json\n\r\n".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
         match request_builder.parse(buffer) {
-            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.headers["CONTENT-TYPE"], vec!["application/json"]),
+            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.headers.get_all("Content-Type"), vec!["application/json"]),
             _ => panic!("Expected Http1Request")
         }
     }
 
     #[test]
     fn test_http_request_builder_two_headers() {
-        let buffer = ByteBuf::from_slice("GET / HTTP 1.1\nContent-Type:   application/json\nContent-Length:128\r\n\n".as_bytes());
-        let request_builder = HttpRequestBuilder::new();
+        let buffer = ByteBuf::from_slice("GET / HTTP/1.1\nContent-Type:   application/json\nContent-Length:4\r\n\nbody".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
         match request_builder.parse(buffer) {
             Ok(HttpResult::Http1Request{request, ..}) => {
-                assert_eq!(request.headers["CONTENT-TYPE"], vec!["application/json"]);
-                assert_eq!(request.headers["CONTENT-LENGTH"], vec!["128"]);
+                assert_eq!(request.headers.get_all("Content-Type"), vec!["application/json"]);
+                assert_eq!(request.headers.get_all("CONTENT-LENGTH"), vec!["4"]);
             }
 
             _ => panic!("Expected Http1Request")
@@ -368,10 +1252,689 @@ mod tests {
     #[test]
     fn test_http_request_builder_http2_upgrade() {
         let buffer = ByteBuf::from_slice("PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".as_bytes());
-        let request_builder = HttpRequestBuilder::new();
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
         match request_builder.parse(buffer) {
-            Ok(HttpResult::Http2Upgrade{..}) => (),
+            Ok(HttpResult::Http2Upgrade{buffer, ..}) => assert_eq!(buffer.bytes(), "SM\r\n\r\n".as_bytes()),
             _ => panic!("Expected Http2Upgrade")
         }
     }
+
+    #[test]
+    fn test_http_request_builder_content_length_body() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
+        match request_builder.parse(buffer) {
+            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.body(), "hello world".as_bytes()),
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_incomplete_body() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
+        match request_builder.parse(buffer) {
+            Ok(HttpResult::Http1Incomplete{mut buffer, request_builder}) => {
+                assert_eq!(request_builder.body, "hello".as_bytes());
+
+                buffer.write_slice(" world".as_bytes());
+                match request_builder.parse(buffer.flip()) {
+                    Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.body(), "hello world".as_bytes()),
+                    _ => panic!("Expected Http1Request")
+                }
+            }
+
+            _ => panic!("Expected Http1Incomplete")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_body_leaves_next_request() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nokGET".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
+        match request_builder.parse(buffer) {
+            Ok(HttpResult::Http1Request{request, buffer}) => {
+                assert_eq!(request.body(), "ok".as_bytes());
+                assert_eq!(buffer.bytes(), "GET".as_bytes());
+            }
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_malformed_content_length() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nContent-Length: 1x\r\n\r\n".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
+        assert!(request_builder.parse(buffer).is_err());
+    }
+
+    #[test]
+    fn test_http_request_builder_negative_content_length() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
+        assert!(request_builder.parse(buffer).is_err());
+    }
+
+    #[test]
+    fn test_http_request_builder_chunked_body() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;name=value\r\n world\r\n0\r\n\r\nGET".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
+        match request_builder.parse(buffer) {
+            Ok(HttpResult::Http1Request{request, buffer}) => {
+                assert_eq!(request.body(), "hello world".as_bytes());
+                assert_eq!(buffer.bytes(), "GET".as_bytes());
+            }
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_chunked_trailers() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\nA\r\n0123456789\r\n0\r\nChecksum: abc\r\nExpires: never\r\n\r\n".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
+        match request_builder.parse(buffer) {
+            Ok(HttpResult::Http1Request{request, ..}) => {
+                assert_eq!(request.body(), "0123456789".as_bytes());
+                assert_eq!(request.trailers().get("Checksum"), Some("abc"));
+                assert_eq!(request.trailers().get("EXPIRES"), Some("never"));
+            }
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_chunked_split_buffers() {
+        let body = "4\r\nWiki\r\n5;ext\r\npedia\r\n0\r\nTrailer: yes\r\n\r\n";
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".as_bytes());
+        let mut result = HttpRequestBuilder::new(ParserLimits::new()).parse(buffer);
+
+        for character in body.as_bytes() {
+            result = match result {
+                Ok(HttpResult::Http1Incomplete{mut buffer, request_builder}) => {
+                    buffer.write_slice(&[*character]);
+                    request_builder.parse(buffer.flip())
+                }
+                _ => panic!("Expected Http1Incomplete")
+            };
+        }
+
+        match result {
+            Ok(HttpResult::Http1Request{request, ..}) => {
+                assert_eq!(request.body(), "Wikipedia".as_bytes());
+                assert_eq!(request.trailers().get("Trailer"), Some("yes"));
+            }
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_take_body_while_streaming() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nContent-Length: 6\r\n\r\nabc".as_bytes());
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Ok(HttpResult::Http1Incomplete{mut buffer, mut request_builder}) => {
+                assert_eq!(request_builder.take_body(), "abc".as_bytes());
+                buffer.write_slice("def".as_bytes());
+
+                match request_builder.parse(buffer.flip()) {
+                    Ok(HttpResult::Http1Request{mut request, ..}) => assert_eq!(request.take_body(), "def".as_bytes()),
+                    _ => panic!("Expected Http1Request")
+                }
+            }
+            _ => panic!("Expected Http1Incomplete")
+        }
+
+        // Bytes already taken still count towards the body limit
+        let limits = ParserLimits::new().max_body_size(8);
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n".as_bytes());
+        match HttpRequestBuilder::new(limits).parse(buffer) {
+            Ok(HttpResult::Http1Incomplete{mut buffer, mut request_builder}) => {
+                assert_eq!(request_builder.take_body(), "Wiki".as_bytes());
+                buffer.write_slice("5\r\npedia\r\n0\r\n\r\n".as_bytes());
+                match request_builder.parse(buffer.flip()) {
+                    Err(err) => assert_eq!(err, HttpError::BodyTooLarge),
+                    _ => panic!("Expected BodyTooLarge")
+                }
+            }
+            _ => panic!("Expected Http1Incomplete")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_invalid_chunk_size() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
+        assert!(request_builder.parse(buffer).is_err());
+    }
+
+    #[test]
+    fn test_http_request_builder_unsupported_transfer_encoding() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n".as_bytes());
+        let request_builder = HttpRequestBuilder::new(ParserLimits::new());
+        assert!(request_builder.parse(buffer).is_err());
+    }
+
+    #[test]
+    fn test_http_request_keep_alive() {
+        let requests = vec![
+            ("GET / HTTP/1.1\r\n\r\n", true),
+            ("GET / HTTP/1.1\r\nConnection: close\r\n\r\n", false),
+            ("GET / HTTP/1.0\r\n\r\n", false),
+            ("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", true),
+            ("GET / HTTP/1.1\r\nConnection: keep-alive, close\r\n\r\n", false),
+            ("GET / HTTP/1.0\r\nConnection: keep-alive, close\r\n\r\n", false),
+            ("GET / HTTP/1.1\r\nConnection: keep-alive\r\nConnection: close\r\n\r\n", false),
+            ("GET / HTTP/1.0\r\nConnection: Upgrade\r\nConnection: keep-alive\r\n\r\n", true)
+        ];
+
+        for (data, keep_alive) in requests {
+            match HttpRequestBuilder::new(ParserLimits::new()).parse(ByteBuf::from_slice(data.as_bytes())) {
+                Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.keep_alive(), keep_alive),
+                _ => panic!("Expected Http1Request")
+            }
+        }
+    }
+
+    fn describe_request(request: &HttpRequest) -> String {
+        format!("{:?} {:?} {:?} {:?} {:?} {:?}", request.method, request.uri, request.version, request.headers,
+            request.body, request.trailers)
+    }
+
+    fn describe(result: &Result<HttpResult, HttpError>) -> String {
+        let rest = |buffer: &ByteBuf| match buffer.remaining() {
+            0 => Vec::new(),
+            _ => buffer.bytes().to_vec()
+        };
+
+        match *result {
+            Ok(HttpResult::Http1Request{ref buffer, ref request}) =>
+                format!("request {} {:?}", describe_request(request), rest(buffer)),
+            Ok(HttpResult::Http2Upgrade{ref buffer, ref request}) =>
+                format!("upgrade {} {:?}", describe_request(request), rest(buffer)),
+            Ok(HttpResult::Http1Expect{ref buffer, ref request_builder}) =>
+                format!("expect {} {:?}", describe_request(&request_builder.preview().unwrap()), rest(buffer)),
+            Ok(HttpResult::Http1Incomplete{..}) => String::from("incomplete"),
+            Err(ref err) => format!("{:?}", err)
+        }
+    }
+
+    /// Parses `data` both through the state machine and with `zero_copy`, which must agree
+    /// on the request or on why it was refused.
+    fn parse_both(data: &[u8], limits: ParserLimits, mode: ParserMode) -> Result<HttpResult, HttpError> {
+        let parse = |zero_copy| HttpRequestBuilder::new(limits).mode(mode).zero_copy(zero_copy).parse(ByteBuf::from_slice(data));
+        let result = parse(false);
+        assert_eq!(describe(&parse(true)), describe(&result), "zero-copy parsing of {:?} in {:?}", String::from_utf8_lossy(data), mode);
+        result
+    }
+
+    #[test]
+    fn test_http_request_builder_zero_copy() {
+        let requests = vec![
+            "GET / HTTP/1.1\r\nHost: example.com\r\nAccept:  */* \r\n\r\n",
+            "\r\nPOST /upload?a=1 HTTP/1.0\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nChecksum: 1\r\n\r\n",
+            "PUT /file HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello",
+            "GET / HTTP/1.1\nHost: a\n\n",
+            "GET / HTTP/1.1\r\nHost: a\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\rb\r\n\r\n",
+            "GET / HTTP/1.1\r\nX: a\u{1}b\r\n\r\n",
+            "GET / HTTP/1.1\r\nX(Y): a\r\n\r\n",
+            "GET / HTTP/1.1\r\nX: a\r\n b\r\n\r\n",
+            "GET / HTTP/1.1\r\nX: caf\u{e9} \u{a0}\r\n\r\n",
+            "GET /caf\u{e9}\u{a0} HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\r\n\rX",
+            "GET / HTTP/1.1\r\nHost: a\r\n",
+            "GET / HTTP/2.0\r\n\r\n",
+            "PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n",
+            "PRI * HTTP/2.0\n\n",
+            "GET /%zz HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"
+        ];
+
+        let small = ParserLimits::new().max_request_line(20).max_header_size(14).max_headers_size(40).max_headers(3);
+        for limits in vec![ParserLimits::new(), small] {
+            for mode in vec![ParserMode::Strict, ParserMode::Lenient] {
+                for data in &requests {
+                    parse_both(data.as_bytes(), limits, mode);
+                }
+
+                // Lines right around each limit, where the two parsers count differently
+                for length in 0 .. 24 {
+                    let padding: String = (0 .. length).map(|_| 'a').collect();
+                    for data in vec![format!("GET /{} HTTP/1.1\r\n\r\n", padding), format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", padding),
+                                     format!("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: {}\r\n\r\n", padding)] {
+                        parse_both(data.as_bytes(), limits, mode);
+                    }
+                }
+            }
+        }
+
+        parse_both(&[b'G', b'E', b'T', b' ', b'/', b' ', b'H', b'T', b'T', b'P', b'/', b'1', b'.', b'1', b'\r', b'\n',
+                     b'X', b':', b' ', 0xff, b'\r', b'\n', b'\r', b'\n'], ParserLimits::new(), ParserMode::Lenient);
+    }
+
+    fn parse_error(data: &str) -> HttpError {
+        match parse_both(data.as_bytes(), ParserLimits::new(), ParserMode::Lenient) {
+            Err(err) => err,
+            _ => panic!("Expected HttpError for {:?}", data)
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_errors() {
+        let requests = vec![
+            ("GE(T / HTTP/1.1\r\n\r\n", HttpError::BadMethod(String::from("GE(T")), 400),
+            (" / HTTP/1.1\r\n\r\n", HttpError::BadMethod(String::new()), 400),
+            ("GET /\r\n\r\n", HttpError::BadRequestLine, 400),
+            ("GET  HTTP/1.1\r\n\r\n", HttpError::BadRequestLine, 400),
+            ("PRI / HTTP/1.1\r\n\r\n", HttpError::MethodNotAllowed(String::from("PRI")), 405),
+            ("PRI * HTTP/2.0\r\nHost: a\r\n\r\n", HttpError::InvalidPreface, 400),
+            ("PRI * HTTP/2.0\n\n", HttpError::InvalidLineEnding, 400),
+            ("GET / HTTP/1.1\r\nNoColon\r\n\r\n", HttpError::MalformedHeader(String::from("NoColon")), 400),
+            ("POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n", HttpError::BodyTooLarge, 413),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: br, chunked\r\n\r\n", HttpError::UnsupportedTransferEncoding(String::from("br, chunked")), 501)
+        ];
+
+        for (data, error, status) in requests {
+            let err = parse_error(data);
+            assert_eq!(err, error);
+            assert_eq!(err.status(), status);
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_invalid_utf8() {
+        let buffer = ByteBuf::from_slice(&[b'G', b'E', b'T', b' ', b'/', 0xff, b' ', b'H', b'\n']);
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Err(err) => {
+                assert_eq!(err, HttpError::InvalidUtf8);
+                assert_eq!(err.status(), 400);
+            }
+            _ => panic!("Expected InvalidUtf8")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_malformed_end_of_headers() {
+        let err = parse_error("GET / HTTP/1.1\r\nHost: a\r\n\rX");
+        assert_eq!(err, HttpError::MalformedHeader(String::from("unexpected 'X' after headers")));
+    }
+
+    #[test]
+    fn test_http_request_builder_ignores_leading_empty_line() {
+        let buffer = ByteBuf::from_slice("\r\nGET / HTTP/1.1\r\n\r\n".as_bytes());
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.path(), "/"),
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_method_parse() {
+        assert_eq!(HttpMethod::parse("HEAD"), Ok(HttpMethod::HEAD));
+        assert_eq!(HttpMethod::parse("CONNECT"), Ok(HttpMethod::CONNECT));
+        assert_eq!(HttpMethod::parse("PROPFIND"), Ok(HttpMethod::Extension(String::from("PROPFIND"))));
+        assert_eq!(HttpMethod::parse("get"), Ok(HttpMethod::Extension(String::from("get"))));
+        assert_eq!(HttpMethod::parse("M-SEARCH").unwrap().as_str(), "M-SEARCH");
+        assert_eq!(HttpMethod::parse("GET/"), Err(HttpError::BadMethod(String::from("GET/"))));
+    }
+
+    #[test]
+    fn test_http_method_properties() {
+        let methods = vec![
+            (HttpMethod::GET, false),
+            (HttpMethod::HEAD, false),
+            (HttpMethod::POST, true),
+            (HttpMethod::PUT, true),
+            (HttpMethod::DELETE, false),
+            (HttpMethod::CONNECT, false),
+            (HttpMethod::OPTIONS, true),
+            (HttpMethod::TRACE, false),
+            (HttpMethod::PATCH, true),
+            (HttpMethod::Extension(String::from("MKCOL")), true)
+        ];
+
+        for (method, allows_body) in methods {
+            assert_eq!(method.allows_body(), allows_body);
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_extension_method() {
+        let buffer = ByteBuf::from_slice("MKCOL /collection/ HTTP/1.1\r\n\r\n".as_bytes());
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Ok(HttpResult::Http1Request{request, ..}) =>
+                assert_eq!(request.method(), &HttpMethod::Extension(String::from("MKCOL"))),
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_version_parse() {
+        assert_eq!(HttpVersion::parse("HTTP/1.0"), Ok(HttpVersion::Http10));
+        assert_eq!(HttpVersion::parse("HTTP/1.1"), Ok(HttpVersion::Http11));
+        assert_eq!(HttpVersion::parse("HTTP/1.2"), Ok(HttpVersion::Http11));
+        assert_eq!(HttpVersion::parse("HTTP/2.0"), Ok(HttpVersion::Http20));
+        assert_eq!(HttpVersion::parse("HTTP/3.0"), Err(HttpError::UnsupportedVersion(String::from("HTTP/3.0"))));
+
+        for version in vec!["HTTP", "HTTP 1.1", "http/1.1", "HTTP/1.10", "HTTP/1", "HTTP/a.b"] {
+            assert_eq!(HttpVersion::parse(version), Err(HttpError::BadRequestLine));
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_unsupported_versions() {
+        let requests = vec![
+            ("GET / HTTP/0.9\r\n\r\n", "HTTP/0.9"),
+            ("GET / HTTP/2.0\r\n\r\n", "HTTP/2.0"),
+            ("GET / HTTP/4.0\r\n\r\n", "HTTP/4.0")
+        ];
+
+        for (data, version) in requests {
+            let err = parse_error(data);
+            assert_eq!(err, HttpError::UnsupportedVersion(String::from(version)));
+            assert_eq!(err.status(), 505);
+        }
+
+        assert_eq!(parse_error("GET / HTTP 1.1\r\n\r\n"), HttpError::BadRequestLine);
+    }
+
+    #[test]
+    fn test_http_request_builder_parses_target() {
+        let buffer = ByteBuf::from_slice("GET /search/caf%C3%A9?q=a+b&page=2 HTTP/1.1\r\n\r\n".as_bytes());
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Ok(HttpResult::Http1Request{request, ..}) => {
+                assert_eq!(request.path(), "/search/caf%C3%A9");
+                assert_eq!(request.uri().decoded_path(), "/search/café");
+                assert_eq!(request.uri().query_param("q"), Some("a b"));
+                assert_eq!(request.uri().query_param("page"), Some("2"));
+            }
+            _ => panic!("Expected Http1Request")
+        }
+
+        assert_eq!(parse_error("GET /%zz HTTP/1.1\r\n\r\n"), HttpError::InvalidPercentEncoding(String::from("/%zz")));
+        assert_eq!(parse_error("GET * HTTP/1.1\r\n\r\n").status(), 400);
+    }
+
+    #[test]
+    fn test_http_request_builder_repeated_headers() {
+        let buffer = ByteBuf::from_slice("GET / HTTP/1.1\r\nAccept: text/html\r\nCookie: a=1\r\nACCEPT: */*\r\ncookie: b=2\r\n\r\n".as_bytes());
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Ok(HttpResult::Http1Request{request, ..}) => {
+                assert_eq!(request.headers().get_all("Cookie"), vec!["a=1", "b=2"]);
+                assert_eq!(request.headers().get_joined("accept"), Some(String::from("text/html, */*")));
+
+                let names: Vec<&str> = request.headers().iter().map(|&(ref name, _)| name.as_ref()).collect();
+                assert_eq!(names, vec!["Accept", "Cookie", "ACCEPT", "cookie"]);
+            }
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_split_transfer_encoding() {
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n".as_bytes());
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Ok(HttpResult::Http1Request{request, ..}) => assert!(request.body().is_empty()),
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_limits() {
+        let limits = ParserLimits::new()
+            .max_request_line(32)
+            .max_header_size(24)
+            .max_headers_size(48)
+            .max_headers(3)
+            .max_body_size(8);
+
+        let requests = vec![
+            ("GET /a/very/long/path/that/goes/on HTTP/1.1\r\n\r\n", HttpError::UriTooLong, 414),
+            ("GET / HTTP/1.1\r\nX-Long: aaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n", HttpError::HeaderTooLarge, 431),
+            ("GET / HTTP/1.1\r\nA: 1111111111111111\r\nB: 2222222222222222\r\nC: 3333333333333333\r\n\r\n", HttpError::HeaderTooLarge, 431),
+            ("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n", HttpError::HeaderTooLarge, 431),
+            ("POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n", HttpError::BodyTooLarge, 413),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n4\r\n", HttpError::BodyTooLarge, 413),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nA: 1\r\nB: 2\r\nC: 3\r\n", HttpError::HeaderTooLarge, 431),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;name=aaaaaaaaaaaaaaaaaaaaaa\r\nhello\r\n", HttpError::HeaderTooLarge, 431)
+        ];
+
+        for (data, error, status) in requests {
+            match parse_both(data.as_bytes(), limits, ParserMode::Lenient) {
+                Err(err) => {
+                    assert_eq!(err, error);
+                    assert_eq!(err.status(), status);
+                }
+                _ => panic!("Expected {:?} for {:?}", error, data)
+            }
+        }
+
+        let buffer = ByteBuf::from_slice("POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\n12345678".as_bytes());
+        match HttpRequestBuilder::new(limits).parse(buffer) {
+            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.body(), "12345678".as_bytes()),
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_limits_across_reads() {
+        let limits = ParserLimits::new().max_header_size(16);
+        let mut result = HttpRequestBuilder::new(limits).parse(ByteBuf::from_slice("GET / HTTP/1.1\r\nX: ".as_bytes()));
+
+        for _ in 0 .. 20 {
+            result = match result {
+                Ok(HttpResult::Http1Incomplete{mut buffer, request_builder}) => {
+                    buffer.write_slice("a".as_bytes());
+                    request_builder.parse(buffer.flip())
+                }
+                Err(err) => {
+                    assert_eq!(err, HttpError::HeaderTooLarge);
+                    return;
+                }
+                _ => panic!("Expected Http1Incomplete")
+            };
+        }
+
+        panic!("Expected HeaderTooLarge");
+    }
+
+    #[test]
+    fn test_http_request_builder_header_line_ends_with_read() {
+        let mut result = HttpRequestBuilder::new(ParserLimits::new()).parse(ByteBuf::from_slice("GET / HTTP/1.1\r\nA: 1\r\n".as_bytes()));
+
+        for data in vec!["B: 2\r\n", "C: 3\r\n", "\r\n"] {
+            result = match result {
+                Ok(HttpResult::Http1Incomplete{mut buffer, request_builder}) => {
+                    buffer.write_slice(data.as_bytes());
+                    request_builder.parse(buffer.flip())
+                }
+                _ => panic!("Expected Http1Incomplete")
+            };
+        }
+
+        match result {
+            Ok(HttpResult::Http1Request{request, ..}) => {
+                assert_eq!(request.header("A"), Some("1"));
+                assert_eq!(request.header("B"), Some("2"));
+                assert_eq!(request.header("C"), Some("3"));
+            }
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    fn parse_with_mode(data: &str, mode: ParserMode) -> Result<HttpResult, HttpError> {
+        parse_both(data.as_bytes(), ParserLimits::new(), mode)
+    }
+
+    #[test]
+    fn test_http_request_builder_lenient_obs_fold() {
+        let data = "GET / HTTP/1.1\r\nX-Folded: first\r\n  second\r\n\tthird\r\nHost: example.com\r\n\r\n";
+        match parse_with_mode(data, ParserMode::Lenient) {
+            Ok(HttpResult::Http1Request{request, ..}) => {
+                assert_eq!(request.header("X-Folded"), Some("first second third"));
+                assert_eq!(request.header("Host"), Some("example.com"));
+            }
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_strict_mode() {
+        let requests = vec![
+            ("GET / HTTP/1.1\nHost: a\r\n\r\n", HttpError::InvalidLineEnding),
+            ("GET / HTTP/1.1\r\nHost: a\n\r\n", HttpError::InvalidLineEnding),
+            ("GET / HTTP/1.1\r\nHost: a\r\n\n", HttpError::InvalidLineEnding),
+            ("GET / HTTP/1.1\r\nHost: a\rb\r\n\r\n", HttpError::InvalidLineEnding),
+            ("\nGET / HTTP/1.1\r\n\r\n", HttpError::InvalidLineEnding),
+            ("GET / HTTP/1.1\r\nX(Y): a\r\n\r\n", HttpError::MalformedHeader(String::from("X(Y)"))),
+            ("GET / HTTP/1.1\r\nX: a\u{1}b\r\n\r\n", HttpError::MalformedHeader(String::from("control character in X"))),
+            ("GET / HTTP/1.1\r\nX: a\r\n b\r\n\r\n", HttpError::MalformedHeader(String::from("obsolete line folding in X"))),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\nA\r\n0\r\n\r\n", HttpError::InvalidLineEnding),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nA\n0\r\n\r\n", HttpError::InvalidLineEnding),
+            ("GET / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", HttpError::BodyNotAllowed(String::from("GET"))),
+            ("DELETE / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n", HttpError::BodyNotAllowed(String::from("DELETE"))),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX(Y): a\r\n\r\n", HttpError::MalformedHeader(String::from("X(Y)"))),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX: a\u{1}b\r\n\r\n",
+             HttpError::MalformedHeader(String::from("control character in X")))
+        ];
+
+        for (data, error) in requests {
+            match parse_with_mode(data, ParserMode::Strict) {
+                Err(err) => assert_eq!(err, error),
+                _ => panic!("Expected {:?} for {:?}", error, data)
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_strict_accepts_valid_requests() {
+        let data = "\r\nPOST /upload HTTP/1.1\r\nHost: a\r\nX-Tab:\tvalue\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nChecksum: 1\r\n\r\n";
+        match parse_with_mode(data, ParserMode::Strict) {
+            Ok(HttpResult::Http1Request{request, ..}) => {
+                assert_eq!(request.header("X-Tab"), Some("value"));
+                assert_eq!(request.body(), "hello".as_bytes());
+            }
+            _ => panic!("Expected Http1Request")
+        }
+
+        // The same inputs strict mode refuses are accepted leniently
+        match parse_with_mode("GET / HTTP/1.1\nHost: a\n\n", ParserMode::Lenient) {
+            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.header("Host"), Some("a")),
+            _ => panic!("Expected Http1Request")
+        }
+
+        match parse_with_mode("GET / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", ParserMode::Lenient) {
+            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.body(), "hello".as_bytes()),
+            _ => panic!("Expected Http1Request")
+        }
+
+        match parse_with_mode("GET / HTTP/1.1\r\nContent-Length: 0\r\n\r\n", ParserMode::Strict) {
+            Ok(HttpResult::Http1Request{request, ..}) => assert!(request.body().is_empty()),
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_smuggling_corpus() {
+        // Payloads from published request smuggling research, each must be refused outright
+        let payloads = vec![
+            // CL.TE and TE.CL
+            ("POST / HTTP/1.1\r\nContent-Length: 13\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nSMUGGLED",
+             HttpError::ContentLengthWithTransferEncoding),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n8\r\nSMUGGLED\r\n0\r\n\r\n",
+             HttpError::ContentLengthWithTransferEncoding),
+            ("POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: identity\r\n\r\n",
+             HttpError::ContentLengthWithTransferEncoding),
+            // TE.TE obfuscation
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: xchunked\r\n\r\n", HttpError::ChunkedNotFinal(String::from("xchunked"))),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: x\r\n\r\n",
+             HttpError::ChunkedNotFinal(String::from("chunked, x"))),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked, identity\r\n\r\n",
+             HttpError::ChunkedNotFinal(String::from("chunked, identity"))),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked, chunked\r\n\r\n",
+             HttpError::ChunkedNotFinal(String::from("chunked, chunked"))),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding : chunked\r\n\r\n",
+             HttpError::WhitespaceInHeaderName(String::from("Transfer-Encoding "))),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding\t: chunked\r\n\r\n",
+             HttpError::WhitespaceInHeaderName(String::from("Transfer-Encoding\t"))),
+            ("POST / HTTP/1.1\r\n Transfer-Encoding: chunked\r\n\r\n",
+             HttpError::WhitespaceInHeaderName(String::from(" Transfer-Encoding"))),
+            // Ambiguous Content-Length
+            ("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+             HttpError::ConflictingContentLength(String::from("5, 6"))),
+            ("POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\nhello!", HttpError::ConflictingContentLength(String::from("5, 6"))),
+            ("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello", HttpError::InvalidContentLength(String::from("+5"))),
+            ("POST / HTTP/1.1\r\nContent-Length: 0x5\r\n\r\nhello", HttpError::InvalidContentLength(String::from("0x5"))),
+            ("POST / HTTP/1.1\r\nContent-Length:\r\n\r\n", HttpError::InvalidContentLength(String::new())),
+            // Framing smuggled in through trailers
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nContent-Length: 10\r\n\r\n",
+             HttpError::MalformedHeader(String::from("Content-Length: 10")))
+        ];
+
+        for (data, error) in payloads {
+            for mode in vec![ParserMode::Strict, ParserMode::Lenient] {
+                match parse_with_mode(data, mode) {
+                    Err(err) => {
+                        assert_eq!(err, error);
+                        assert_eq!(err.status(), 400);
+                    }
+                    _ => panic!("Expected {:?} for {:?}", error, data)
+                }
+            }
+        }
+
+        // HTTP/1.0 has no chunked coding, lenient mode decodes it but won't read another request after it
+        let data = "POST / HTTP/1.0\r\nConnection: keep-alive\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.0\r\n\r\n";
+        match parse_with_mode(data, ParserMode::Strict) {
+            Err(err) => {
+                assert_eq!(err, HttpError::TransferEncodingInHttp10);
+                assert_eq!(err.status(), 400);
+            },
+            _ => panic!("Expected TransferEncodingInHttp10")
+        }
+
+        match parse_with_mode(data, ParserMode::Lenient) {
+            Ok(HttpResult::Http1Request{request, ..}) => assert!(!request.keep_alive()),
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_repeated_identical_content_length() {
+        let data = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello";
+        match parse_with_mode(data, ParserMode::Strict) {
+            Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.body(), "hello".as_bytes()),
+            _ => panic!("Expected Http1Request")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_expect() {
+        let buffer = ByteBuf::from_slice("PUT /file HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello".as_bytes());
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Ok(HttpResult::Http1Expect{buffer, request_builder}) => {
+                assert_eq!(request_builder.expectation(), Some("100-continue"));
+                assert_eq!(request_builder.preview().unwrap().path(), "/file");
+                assert_eq!(buffer.bytes(), "hello".as_bytes());
+
+                match request_builder.parse(buffer) {
+                    Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.body(), "hello".as_bytes()),
+                    _ => panic!("Expected Http1Request")
+                }
+            }
+            _ => panic!("Expected Http1Expect")
+        }
+    }
+
+    #[test]
+    fn test_http_request_builder_expect_without_body() {
+        let buffer = ByteBuf::from_slice("GET / HTTP/1.1\r\nExpect: 100-continue\r\n\r\n".as_bytes());
+        match HttpRequestBuilder::new(ParserLimits::new()).parse(buffer) {
+            Ok(HttpResult::Http1Expect{buffer, request_builder}) => match request_builder.parse(buffer) {
+                Ok(HttpResult::Http1Request{request, ..}) => assert_eq!(request.header("Expect"), Some("100-continue")),
+                _ => panic!("Expected Http1Request")
+            },
+            _ => panic!("Expected Http1Expect")
+        }
+    }
 }
@@ -0,0 +1,406 @@
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::ascii::AsciiExt;
+use bytes::{MutBuf, MutByteBuf};
+use request::{self, HttpVersion};
+use headers::HeaderMap;
+use body::{self, BodyReceiver, BodySink};
+
+pub struct HttpResponse {
+    status: u16,
+    reason: String,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    chunked: bool,
+    stream: Option<BodyReceiver>
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        417 => "Expectation Failed",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown"
+    }
+}
+
+impl HttpResponse {
+    pub fn new(status: u16) -> HttpResponse {
+        HttpResponse::with_reason(status, reason_phrase(status))
+    }
+
+    pub fn with_reason(status: u16, reason: &str) -> HttpResponse {
+        HttpResponse {
+            status: status,
+            reason: String::from(reason),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            chunked: false,
+            stream: None
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> HttpResponse {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> HttpResponse {
+        self.body = body;
+        self
+    }
+
+    /// Asks for chunked encoding, used only when the client is HTTP/1.1. Older clients
+    /// get the same body with a Content-Length.
+    pub fn chunked(mut self) -> HttpResponse {
+        self.chunked = true;
+        self
+    }
+
+    /// Turns this into a response whose body is written through the returned sink while
+    /// it is being sent, starting with any body already set. HTTP/1.1 clients get it with
+    /// chunked encoding, HTTP/1.0 clients get it delimited by closing the connection.
+    pub fn streaming(mut self) -> (HttpResponse, BodySink) {
+        let (sink, receiver) = body::sink(mem::replace(&mut self.body, Vec::new()));
+        self.stream = Some(receiver);
+        (self, sink)
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.chunked
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Takes the receiving end of a streamed body, for the connection sending it.
+    pub fn take_stream(&mut self) -> Option<BodyReceiver> {
+        self.stream.take()
+    }
+
+    // 1xx, 204 and 304 responses never carry a body or framing headers
+    pub fn allows_body(&self) -> bool {
+        match self.status {
+            100 ... 199 | 204 | 304 => false,
+            _ => true
+        }
+    }
+
+    /// The whole response, framed for a client speaking the given version.
+    pub fn to_bytes(&self, version: HttpVersion) -> Vec<u8> {
+        self.write_bytes(version, true)
+    }
+
+    /// The status line and headers only, as sent in answer to a HEAD request.
+    pub fn head_bytes(&self, version: HttpVersion) -> Vec<u8> {
+        self.write_bytes(version, false)
+    }
+
+    /// The status line and headers of a streamed response, framed with chunked encoding
+    /// or left to be delimited by closing the connection.
+    pub fn streaming_head_bytes(&self, chunked: bool) -> Vec<u8> {
+        let mut data = self.write_head();
+
+        if chunked && self.allows_body() {
+            data.extend(b"Transfer-Encoding: chunked\r\n".iter().cloned());
+        }
+
+        data.extend(b"\r\n".iter().cloned());
+        data
+    }
+
+    /// Everything up to the framing headers, which are always worked out here rather
+    /// than taken from the handler.
+    fn write_head(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(128 + self.body.len());
+
+        // A reason that could end the status line early is replaced by the standard one
+        let reason = match self.reason.bytes().all(is_field_value_byte) {
+            true => &self.reason,
+            false => reason_phrase(self.status)
+        };
+
+        // HTTP/1.0 clients are answered as HTTP/1.1 too, the highest minor version we conform to
+        write!(&mut data, "{} {} {}\r\n", HttpVersion::Http11, self.status, reason).unwrap();
+
+        for &(ref name, ref value) in self.headers.iter() {
+            if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+                continue;
+            }
+
+            write_field(&mut data, name, value);
+        }
+
+        data
+    }
+
+    fn write_bytes(&self, version: HttpVersion, include_body: bool) -> Vec<u8> {
+        let mut data = self.write_head();
+
+        if !self.allows_body() {
+            data.extend(b"\r\n".iter().cloned());
+            return data;
+        }
+
+        if self.chunked && version == HttpVersion::Http11 {
+            data.extend(b"Transfer-Encoding: chunked\r\n\r\n".iter().cloned());
+
+            if !include_body {
+                return data;
+            }
+
+            data.extend(chunk_bytes(&self.body));
+            data.extend(last_chunk_bytes(&HeaderMap::new()));
+        } else {
+            write!(&mut data, "Content-Length: {}\r\n\r\n", self.body.len()).unwrap();
+
+            if include_body {
+                data.extend(self.body.iter().cloned());
+            }
+        }
+
+        data
+    }
+
+    pub fn serialize(&self, buffer: &mut MutByteBuf) -> io::Result<usize> {
+        let data = self.to_bytes(HttpVersion::Http11);
+
+        if buffer.remaining() < data.len() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Response does not fit in buffer"));
+        }
+
+        Ok(buffer.write_slice(&data))
+    }
+}
+
+/// One chunk of a chunked body, nothing for empty data since that would end the body.
+pub fn chunk_bytes(data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+
+    if !data.is_empty() {
+        write!(&mut chunk, "{:x}\r\n", data.len()).unwrap();
+        chunk.extend(data.iter().cloned());
+        chunk.extend(b"\r\n".iter().cloned());
+    }
+
+    chunk
+}
+
+/// The zero sized chunk ending a chunked body, followed by any trailers.
+pub fn last_chunk_bytes(trailers: &HeaderMap) -> Vec<u8> {
+    let mut data = Vec::with_capacity(5);
+    data.extend(b"0\r\n".iter().cloned());
+
+    for &(ref name, ref value) in trailers.iter() {
+        write_field(&mut data, name, value);
+    }
+
+    data.extend(b"\r\n".iter().cloned());
+    data
+}
+
+/// Whether a byte may appear in a field value or reason phrase, which RFC 9110 limits to
+/// visible characters, obs-text, spaces and tabs.
+fn is_field_value_byte(byte: u8) -> bool {
+    byte == b'\t' || (byte >= b' ' && byte != 0x7f)
+}
+
+/// Writes a header or trailer field, dropping any that would break out of its line and
+/// let a handler inject fields of its own or split the response.
+fn write_field(data: &mut Vec<u8>, name: &str, value: &str) {
+    let valid_name = !name.is_empty() && name.bytes().all(request::is_token_char);
+    let valid_value = value.bytes().all(is_field_value_byte);
+
+    if !valid_name || !valid_value {
+        warn!("Dropping invalid header field {:?}", name);
+        return;
+    }
+
+    write!(data, "{}: {}\r\n", name, value).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::ByteBuf;
+    use headers::HeaderMap;
+    use request::HttpVersion;
+    use super::{chunk_bytes, last_chunk_bytes, HttpResponse};
+
+    #[test]
+    fn test_http_response_content_length() {
+        let response = HttpResponse::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_body("hello".as_bytes().to_vec());
+        let mut buffer = ByteBuf::mut_with_capacity(1024);
+
+        response.serialize(&mut buffer).unwrap();
+        assert_eq!(buffer.bytes(), "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello".as_bytes());
+    }
+
+    #[test]
+    fn test_http_response_chunked() {
+        let response = HttpResponse::new(200)
+            .with_body("hello world".as_bytes().to_vec())
+            .chunked();
+        let mut buffer = ByteBuf::mut_with_capacity(1024);
+
+        response.serialize(&mut buffer).unwrap();
+        assert_eq!(buffer.bytes(), "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nb\r\nhello world\r\n0\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn test_http_response_chunked_for_http10() {
+        let response = HttpResponse::new(200)
+            .with_body("hello world".as_bytes().to_vec())
+            .chunked();
+
+        assert_eq!(response.to_bytes(HttpVersion::Http10), "HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world".as_bytes());
+        assert_eq!(response.head_bytes(HttpVersion::Http10), "HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn test_http_response_drops_invalid_fields() {
+        let mut response = HttpResponse::new(200)
+            .with_header("Location", "/a\r\nSet-Cookie: admin=1")
+            .with_header("X-Split", "a\nHTTP/1.1 200 OK")
+            .with_header("Bad Name", "x")
+            .with_header("X-Null", "a\u{0}b")
+            .with_header("X-Bell", "a\u{7}b")
+            .with_header("X-Escape", "\u{1b}[2J")
+            .with_header("X-Delete", "a\u{7f}")
+            .with_header("X-Ok", "fine\tcaf\u{e9}")
+            .with_header("X:Colon", "x")
+            .with_header("X/Slash", "x");
+        response.headers_mut().append("X\r\nInjected", "1");
+
+        assert_eq!(response.to_bytes(HttpVersion::Http11), "HTTP/1.1 200 OK\r\nX-Ok: fine\tcaf\u{e9}\r\nContent-Length: 0\r\n\r\n".as_bytes());
+        assert_eq!(HttpResponse::with_reason(404, "Gone\r\nX: 1").head_bytes(HttpVersion::Http11),
+                   "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".as_bytes());
+        assert_eq!(HttpResponse::with_reason(404, "Gone\u{b}").head_bytes(HttpVersion::Http11),
+                   "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".as_bytes());
+
+        let mut trailers = HeaderMap::new();
+        trailers.append("Checksum", "1\r\n\r\nGET /smuggled HTTP/1.1");
+        trailers.append("Rows", "2");
+        assert_eq!(last_chunk_bytes(&trailers), "0\r\nRows: 2\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn test_http_response_replaces_framing_headers() {
+        let response = HttpResponse::with_reason(404, "Missing")
+            .with_header("content-length", "100")
+            .with_body("gone".as_bytes().to_vec());
+        let mut buffer = ByteBuf::mut_with_capacity(1024);
+
+        response.serialize(&mut buffer).unwrap();
+        assert_eq!(buffer.bytes(), "HTTP/1.1 404 Missing\r\nContent-Length: 4\r\n\r\ngone".as_bytes());
+    }
+
+    #[test]
+    fn test_http_response_no_content() {
+        let response = HttpResponse::new(204).with_body("ignored".as_bytes().to_vec());
+        let mut buffer = ByteBuf::mut_with_capacity(1024);
+
+        response.serialize(&mut buffer).unwrap();
+        assert_eq!(buffer.bytes(), "HTTP/1.1 204 No Content\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn test_http_response_repeated_headers() {
+        let response = HttpResponse::new(200)
+            .with_header("Set-Cookie", "a=1")
+            .with_header("set-cookie", "b=2");
+
+        assert_eq!(response.headers().get_all("Set-Cookie"), vec!["a=1", "b=2"]);
+        assert_eq!(response.to_bytes(HttpVersion::Http11), "HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nset-cookie: b=2\r\nContent-Length: 0\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn test_http_response_head_bytes() {
+        let response = HttpResponse::new(200).with_body("hello".as_bytes().to_vec());
+        assert_eq!(response.head_bytes(HttpVersion::Http11), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn test_http_response_buffer_too_small() {
+        let response = HttpResponse::new(200).with_body(vec![0; 64]);
+        let mut buffer = ByteBuf::mut_with_capacity(16);
+
+        assert!(response.serialize(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_http_response_streaming_head() {
+        let (response, _sink) = HttpResponse::new(200)
+            .with_header("Content-Type", "text/csv")
+            .with_header("Content-Length", "10")
+            .streaming();
+
+        assert!(response.is_streaming());
+        assert_eq!(response.streaming_head_bytes(true), "HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\nTransfer-Encoding: chunked\r\n\r\n".as_bytes());
+        assert_eq!(response.streaming_head_bytes(false), "HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn test_http_response_chunk_encoding() {
+        let mut trailers = HeaderMap::new();
+        trailers.append("Rows", "2");
+
+        assert_eq!(chunk_bytes("a,b\n".as_bytes()), "4\r\na,b\n\r\n".as_bytes());
+        assert!(chunk_bytes(&[]).is_empty());
+        assert_eq!(last_chunk_bytes(&trailers), "0\r\nRows: 2\r\n\r\n".as_bytes());
+    }
+}
@@ -0,0 +1,1791 @@
+use mio;
+use mio::*;
+use mio::tcp::*;
+use mio::util::Slab;
+use bytes::{Buf, ByteBuf, MutByteBuf};
+use std::io;
+use std::error::Error;
+use std::ascii::AsciiExt;
+use std::net::SocketAddr;
+use std::collections::VecDeque;
+use std::mem;
+use body::{self, BodyReceiver, BodySender};
+use handler::Handler;
+use http2::{ErrorCode, Http2Connection, Settings};
+use promises::{self, Promise};
+use request::{HttpError, HttpMethod, HttpVersion, HttpRequest, HttpRequestBuilder, HttpResult, ParserLimits, ParserMode};
+use response::{self, HttpResponse};
+
+const SERVER : Token = Token(0);
+
+// Rounds of a streamed response body written in one event before other connections get a turn
+const MAX_BODY_ROUNDS: usize = 16;
+
+pub enum HttpMessage {
+    ResponseReady(Token),
+    BodyRead(Token),
+    Shutdown
+}
+
+/// Asks a running server to stop accepting connections and finish in-flight requests.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Sender<HttpMessage>
+}
+
+impl ShutdownHandle {
+    /// Returns false if the server has already stopped.
+    pub fn shutdown(&self) -> bool {
+        self.sender.send(HttpMessage::Shutdown).is_ok()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ConnectionOptions {
+    buffer_size: usize,
+    max_requests: Option<usize>,
+    max_pipelined: usize,
+    keep_alive_timeout_ms: u64,
+    http2_idle_timeout_ms: u64,
+    header_timeout_ms: u64,
+    body_timeout_ms: u64,
+    write_timeout_ms: u64,
+    limits: ParserLimits,
+    mode: ParserMode,
+    zero_copy: bool
+}
+
+impl ConnectionOptions {
+    fn request_builder(&self) -> HttpRequestBuilder {
+        HttpRequestBuilder::new(self.limits).mode(self.mode).zero_copy(self.zero_copy)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum TimeoutKind {
+    //Waiting for the next request on an open connection
+    KeepAlive,
+
+    //Nothing received or sent on a connection that switched to HTTP/2
+    Http2Idle,
+
+    //Request timeouts
+    Headers,
+    Body,
+
+    //Response timeouts
+    Write
+}
+
+pub struct Server {
+    addr: SocketAddr,
+    max_connections: usize,
+    shutdown_timeout_ms: u64,
+    options: ConnectionOptions,
+    handler: Box<Handler>
+}
+
+impl Server {
+    pub fn new<H>(handler: H) -> Server where H: Handler + 'static {
+        Server {
+            addr: "127.0.0.1:8080".parse().unwrap(),
+            max_connections: 128,
+            shutdown_timeout_ms: 30_000,
+            options: ConnectionOptions {
+                buffer_size: 2048*8,
+                max_requests: None,
+                max_pipelined: 16,
+                keep_alive_timeout_ms: 15_000,
+                http2_idle_timeout_ms: 60_000,
+                header_timeout_ms: 10_000,
+                body_timeout_ms: 30_000,
+                write_timeout_ms: 30_000,
+                limits: ParserLimits::new(),
+                mode: ParserMode::Lenient,
+                zero_copy: false
+            },
+            handler: Box::new(handler)
+        }
+    }
+
+    pub fn bind(mut self, addr: SocketAddr) -> Server {
+        self.addr = addr;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Server {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn buffer_size(mut self, buffer_size: usize) -> Server {
+        self.options.buffer_size = buffer_size;
+        self
+    }
+
+    /// Limits on request line, header and body sizes, see `ParserLimits`.
+    pub fn parser_limits(mut self, limits: ParserLimits) -> Server {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Whether requests are parsed strictly or leniently, see `ParserMode`.
+    pub fn parser_mode(mut self, mode: ParserMode) -> Server {
+        self.options.mode = mode;
+        self
+    }
+
+    /// Parses request heads that arrive in a single read straight from the read buffer,
+    /// see `HttpRequestBuilder::zero_copy`.
+    pub fn zero_copy_parsing(mut self, zero_copy: bool) -> Server {
+        self.options.zero_copy = zero_copy;
+        self
+    }
+
+    /// Closes keep-alive connections once they have served this many requests.
+    pub fn max_requests_per_connection(mut self, max_requests: usize) -> Server {
+        self.options.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Stops reading from a connection while this many pipelined requests await a response.
+    pub fn max_pipelined_requests(mut self, max_pipelined: usize) -> Server {
+        self.options.max_pipelined = max_pipelined;
+        self
+    }
+
+    /// Closes connections that sit idle between requests for longer than this.
+    pub fn keep_alive_timeout_ms(mut self, timeout: u64) -> Server {
+        self.options.keep_alive_timeout_ms = timeout;
+        self
+    }
+
+    /// Closes HTTP/2 connections with a GOAWAY once nothing has been received or sent on
+    /// them for this long.
+    pub fn http2_idle_timeout_ms(mut self, timeout: u64) -> Server {
+        self.options.http2_idle_timeout_ms = timeout;
+        self
+    }
+
+    /// Answers `408 Request Timeout` if a request's headers take longer than this to arrive.
+    pub fn header_timeout_ms(mut self, timeout: u64) -> Server {
+        self.options.header_timeout_ms = timeout;
+        self
+    }
+
+    /// Closes connections when no request body bytes arrive for this long.
+    pub fn body_timeout_ms(mut self, timeout: u64) -> Server {
+        self.options.body_timeout_ms = timeout;
+        self
+    }
+
+    /// Closes connections when the client stops accepting response bytes for this long.
+    pub fn write_timeout_ms(mut self, timeout: u64) -> Server {
+        self.options.write_timeout_ms = timeout;
+        self
+    }
+
+    /// How long a shutdown waits for in-flight requests before dropping their connections.
+    pub fn shutdown_timeout_ms(mut self, timeout: u64) -> Server {
+        self.shutdown_timeout_ms = timeout;
+        self
+    }
+
+    /// Binds the listening socket without starting the event loop, so a
+    /// `ShutdownHandle` can be taken before calling `run`.
+    pub fn listen(self) -> io::Result<Listening> {
+        let listener = try!(TcpListener::bind(&self.addr));
+        let mut event_loop = try!(EventLoop::new());
+        try!(event_loop.register(&listener, SERVER));
+
+        Ok(Listening {
+            handler: HttpHandler::new(listener, self),
+            event_loop: event_loop
+        })
+    }
+
+    pub fn run(self) -> io::Result<()> {
+        try!(self.listen()).run()
+    }
+}
+
+pub struct Listening {
+    handler: HttpHandler,
+    event_loop: EventLoop<HttpHandler>
+}
+
+impl Listening {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.handler.server.sock.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            sender: self.event_loop.channel()
+        }
+    }
+
+    pub fn run(mut self) -> io::Result<()> {
+        self.event_loop.run(&mut self.handler)
+    }
+}
+
+struct PendingResponse {
+    promise: Promise<HttpResponse>,
+    connection_header: Option<&'static str>,
+    version: HttpVersion,
+    head: bool,
+    //Whether the request's body was still being read when it was handed over
+    streamed: bool
+}
+
+struct StreamingResponse {
+    receiver: BodyReceiver,
+    chunked: bool
+}
+
+struct HttpConnection {
+    sock: TcpStream,
+    buf: Option<ByteBuf>,
+    mut_buf: Option<MutByteBuf>,
+    unparsed: Option<ByteBuf>,
+    token: Option<Token>,
+    interest: EventSet,
+    timer: Option<(Timeout, TimeoutKind, usize)>,
+    http_request: Option<HttpRequestBuilder>,
+    body: Option<BodySender>,
+    body_checked: bool,
+    pipeline: VecDeque<PendingResponse>,
+    response_body: Option<StreamingResponse>,
+    http2: Option<Http2Connection>,
+    requests: usize,
+    options: ConnectionOptions,
+    read_closed: bool,
+    draining: bool,
+    closing: bool
+}
+
+impl HttpConnection {
+    fn new(sock: TcpStream, options: ConnectionOptions) -> HttpConnection {
+        HttpConnection {
+            sock: sock,
+            buf: None,
+            mut_buf: Some(ByteBuf::mut_with_capacity(options.buffer_size)),
+            unparsed: None,
+            token: None,
+            interest: EventSet::readable() | EventSet::hup(),
+            timer: None,
+            http_request: Some(options.request_builder()),
+            body: None,
+            body_checked: false,
+            pipeline: VecDeque::new(),
+            response_body: None,
+            http2: None,
+            requests: 0,
+            options: options,
+            read_closed: false,
+            draining: false,
+            closing: false
+        }
+    }
+
+    fn readable(&mut self, event_loop: &mut EventLoop<HttpHandler>, handler: &Handler) -> io::Result<()> {
+        let mut buf = match self.mut_buf.take() {
+            Some(buf) => buf,
+            None => return Ok(())
+        };
+
+        match self.sock.try_read_buf(&mut buf) {
+            Ok(None) => {
+                self.mut_buf = Some(buf);
+            }
+            Ok(Some(0)) => {
+                // The peer has shut down its side, so no further requests will arrive but
+                // responses to those already read are still written. A body still being
+                // streamed to the handler can't be completed.
+                self.mut_buf = Some(buf);
+                self.read_closed = true;
+                self.body = None;
+            }
+            Ok(Some(_)) => {
+                self.unparsed = Some(buf.flip());
+            }
+            Err(e) => {
+                self.mut_buf = Some(buf);
+                return Err(e);
+            }
+        }
+
+        self.process(event_loop, handler)
+    }
+
+    fn writable(&mut self, event_loop: &mut EventLoop<HttpHandler>, handler: &Handler) -> io::Result<()> {
+        self.process(event_loop, handler)
+    }
+
+    fn response_ready(&mut self, event_loop: &mut EventLoop<HttpHandler>, handler: &Handler) -> io::Result<()> {
+        self.process(event_loop, handler)
+    }
+
+    /// Header timeouts are answered with `408 Request Timeout` after any responses already
+    /// in the pipeline and idle HTTP/2 connections are sent a GOAWAY, every other timeout
+    /// drops the connection straight away.
+    fn timed_out(&mut self, event_loop: &mut EventLoop<HttpHandler>) -> io::Result<()> {
+        let kind = match self.timer.take() {
+            Some((_, kind, _)) => kind,
+            None => return Ok(())
+        };
+
+        match kind {
+            TimeoutKind::Headers => (),
+            TimeoutKind::Http2Idle => {
+                self.drain();
+                return self.flush();
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{:?} timeout elapsed", kind)))
+        }
+
+        self.closing = true;
+        self.http_request = None;
+        self.pipeline.push_back(PendingResponse {
+            promise: promises::completed(Ok(HttpResponse::new(408))),
+            connection_header: Some("close"),
+            version: HttpVersion::Http11,
+            head: false,
+            streamed: false
+        });
+
+        try!(self.write_ready_responses(event_loop));
+        self.flush()
+    }
+
+    fn timeout_kind(&self) -> Option<TimeoutKind> {
+        let (started, reading_body) = match self.http_request {
+            Some(ref http_request) => (http_request.is_started(), http_request.is_reading_body()),
+            None => (false, false)
+        };
+
+        if self.buf.is_some() {
+            Some(TimeoutKind::Write)
+        } else if self.http2.is_some() {
+            Some(TimeoutKind::Http2Idle)
+        } else if reading_body {
+            // A body held up by a slow handler rather than the client isn't timed
+            match self.is_body_paused() {
+                true => None,
+                false => Some(TimeoutKind::Body)
+            }
+        } else if started {
+            Some(TimeoutKind::Headers)
+        } else if self.pipeline.is_empty() && self.unparsed.is_none() && self.response_body.is_none() {
+            Some(TimeoutKind::KeepAlive)
+        } else {
+            None
+        }
+    }
+
+    /// Keep-alive and header timers run from the moment the connection enters that state
+    /// for a request, while body and write timers restart whenever the connection sees activity.
+    fn schedule_timeout(&mut self, event_loop: &mut EventLoop<HttpHandler>) {
+        let kind = self.timeout_kind();
+
+        if let Some((timeout, current, requests)) = self.timer {
+            let deadline = current == TimeoutKind::KeepAlive || current == TimeoutKind::Headers;
+            if deadline && Some(current) == kind && requests == self.requests {
+                return;
+            }
+
+            event_loop.clear_timeout(timeout);
+            self.timer = None;
+        }
+
+        let kind = match kind {
+            Some(kind) => kind,
+            None => return
+        };
+
+        let delay = match kind {
+            TimeoutKind::KeepAlive => self.options.keep_alive_timeout_ms,
+            TimeoutKind::Http2Idle => self.options.http2_idle_timeout_ms,
+            TimeoutKind::Headers => self.options.header_timeout_ms,
+            TimeoutKind::Body => self.options.body_timeout_ms,
+            TimeoutKind::Write => self.options.write_timeout_ms
+        };
+
+        let token = self.token.expect("No token assigned to connection");
+        match event_loop.timeout_ms(token, delay) {
+            Ok(timeout) => self.timer = Some((timeout, kind, self.requests)),
+            Err(e) => warn!("Could not schedule {:?} timeout {:?}", kind, e)
+        }
+    }
+
+    /// Writes out every response at the head of the pipeline that has completed, then
+    /// parses further buffered requests while there is room in the pipeline.
+    fn process(&mut self, event_loop: &mut EventLoop<HttpHandler>, handler: &Handler) -> io::Result<()> {
+        let mut body_rounds = 0;
+
+        loop {
+            try!(self.write_ready_responses(event_loop));
+
+            let buffer = match self.unparsed.take() {
+                Some(buffer) => buffer,
+                None => {
+                    try!(self.flush());
+
+                    // A streamed body keeps going while the socket takes it straight away, up to
+                    // a limit after which `reregister` waits for the socket to be writable again
+                    body_rounds += 1;
+                    match self.buf.is_none() && self.has_response_data() && body_rounds < MAX_BODY_ROUNDS {
+                        true => continue,
+                        false => break
+                    }
+                }
+            };
+
+            // A request already passed to the handler still gets the rest of its body
+            if self.closing && self.body.is_none() {
+                self.mut_buf = Some(buffer.flip());
+                break;
+            }
+
+            if self.http2.is_some() {
+                self.receive_http2(buffer);
+                continue;
+            }
+
+            if self.pipeline.len() >= self.options.max_pipelined {
+                self.unparsed = Some(buffer);
+                break;
+            }
+
+            self.parse(event_loop, handler, buffer);
+        }
+
+        self.flush()
+    }
+
+    fn parse(&mut self, event_loop: &mut EventLoop<HttpHandler>, handler: &Handler, buffer: ByteBuf) {
+        let options = self.options;
+        let http_request = self.http_request.take().unwrap_or_else(|| options.request_builder());
+
+        match http_request.parse(buffer) {
+            Ok(HttpResult::Http1Incomplete{buffer, mut request_builder}) => {
+                self.mut_buf = Some(buffer);
+
+                if request_builder.is_reading_body() {
+                    if !self.body_checked {
+                        self.body_checked = true;
+                        if let Some(request) = request_builder.preview() {
+                            if handler.stream_body(&request) {
+                                self.stream_request(event_loop, handler, request);
+                            }
+                        }
+                    }
+
+                    if let Some(ref body) = self.body {
+                        body.push(request_builder.take_body());
+                    }
+                }
+
+                self.http_request = Some(request_builder);
+            },
+            Ok(HttpResult::Http1Request{buffer, mut request}) => {
+                if buffer.has_remaining() {
+                    self.unparsed = Some(buffer);
+                } else {
+                    self.mut_buf = Some(buffer.flip());
+                }
+
+                // The handler is only asked here if the whole body came in with the headers
+                let checked = mem::replace(&mut self.body_checked, false);
+                let streaming = self.body.is_some()
+                    || (!checked && !request.body().is_empty() && handler.stream_body(&request));
+
+                if !streaming {
+                    return self.handle(event_loop, handler, request);
+                }
+
+                let data = request.take_body();
+                let trailers = request.trailers().clone();
+                if self.body.is_none() {
+                    self.stream_request(event_loop, handler, request);
+                }
+
+                if let Some(body) = self.body.take() {
+                    body.push(data);
+                    body.finish(trailers);
+                }
+            },
+            Ok(HttpResult::Http1Expect{buffer, request_builder}) => {
+                // Parsing goes on even with nothing left over, since a request without a
+                // body is already complete
+                self.expect(handler, request_builder);
+                self.unparsed = Some(buffer);
+            },
+            Ok(HttpResult::Http2Upgrade{buffer, ..}) => {
+                // Only a connection that hasn't carried any HTTP/1 requests can switch
+                if self.requests > 0 || !self.pipeline.is_empty() {
+                    return self.reject(HttpError::InvalidPreface);
+                }
+
+                // The rest of the preface and the first frames may already be in the buffer
+                self.http2 = Some(Http2Connection::new(Settings::new()));
+                self.receive_http2(buffer);
+            },
+            Err(err) => self.reject(err)
+        }
+    }
+
+    /// Answers a request that can't be served with the error's status, then closes.
+    fn reject(&mut self, err: HttpError) {
+        debug!("Rejecting request {}", err);
+
+        self.body_checked = false;
+        self.closing = true;
+        self.http_request = None;
+
+        // A request whose body was being streamed is answered with the error instead,
+        // unless the handler already responded, in which case that response is the last
+        // one sent whether or not it has gone out yet. Dropping the sender fails its reader.
+        if self.body.take().is_some() {
+            let answered = self.pipeline.back()
+                .map(|pending| !pending.streamed || pending.promise.is_complete())
+                .unwrap_or(true);
+            if answered {
+                return;
+            }
+
+            self.pipeline.pop_back();
+        }
+
+        self.pipeline.push_back(PendingResponse {
+            promise: promises::completed(Ok(HttpResponse::new(err.status()))),
+            connection_header: Some("close"),
+            version: HttpVersion::Http11,
+            head: false,
+            streamed: false
+        });
+    }
+
+    /// Feeds bytes read from a connection that has switched to HTTP/2 and queues whatever
+    /// it answers. The connection closes once a GOAWAY has been sent or received.
+    fn receive_http2(&mut self, mut buffer: ByteBuf) {
+        let (output, closed) = match self.http2 {
+            Some(ref mut http2) => {
+                if let Err(code) = http2.receive(&mut buffer) {
+                    warn!("HTTP/2 connection error {:?}", code);
+                }
+
+                (http2.take_output(), http2.is_closed())
+            },
+            None => return
+        };
+
+        if !output.is_empty() {
+            self.queue(&output);
+        }
+
+        if closed {
+            self.closing = true;
+        }
+
+        self.mut_buf = Some(buffer.flip());
+    }
+
+    /// Answers an `Expect` header before any of the body is read. `100 Continue` is queued
+    /// behind earlier responses if the handler accepts, otherwise its final response or a
+    /// `417 Expectation Failed` for unknown expectations ends the connection unread.
+    fn expect(&mut self, handler: &Handler, request_builder: HttpRequestBuilder) {
+        let continue_expected = request_builder.expectation()
+            .map(|expectation| expectation.trim().eq_ignore_ascii_case("100-continue"))
+            .unwrap_or(false);
+        let preview = request_builder.preview();
+        let version = preview.as_ref().map(HttpRequest::version).unwrap_or(HttpVersion::Http11);
+        self.http_request = Some(request_builder);
+
+        let rejection = match preview {
+            // HTTP/1.0 clients don't know about 100 Continue and send the body regardless
+            Some(ref request) if continue_expected && request.version() == HttpVersion::Http10 => return,
+            Some(ref request) if continue_expected => handler.check_continue(request),
+            _ => Some(HttpResponse::new(417))
+        };
+
+        let pending = match rejection {
+            None => PendingResponse {
+                promise: promises::completed(Ok(HttpResponse::new(100))),
+                connection_header: None,
+                version: version,
+                head: false,
+                streamed: false
+            },
+            Some(response) => {
+                self.closing = true;
+                self.http_request = None;
+                PendingResponse {
+                    promise: promises::completed(Ok(response)),
+                    connection_header: Some("close"),
+                    version: version,
+                    head: false,
+                    streamed: false
+                }
+            }
+        };
+
+        self.pipeline.push_back(pending);
+    }
+
+    fn handle(&mut self, event_loop: &mut EventLoop<HttpHandler>, handler: &Handler, request: HttpRequest) {
+        let connection_header = self.start_request(&request);
+        let version = request.version();
+        let head = request.method() == &HttpMethod::HEAD;
+        let pending = PendingResponse {
+            promise: handler.handle(request),
+            connection_header: connection_header,
+            version: version,
+            head: head,
+            streamed: self.body.is_some()
+        };
+
+        self.dispatch(event_loop, pending);
+    }
+
+    /// Hands a request to the handler before its body has been read. Reading from the socket
+    /// pauses while more than a buffer's worth of the body is waiting for the handler, and
+    /// resumes once it has read enough.
+    fn stream_request(&mut self, event_loop: &mut EventLoop<HttpHandler>, handler: &Handler, request: HttpRequest) {
+        let token = self.token.expect("No token assigned to connection");
+        let sender = event_loop.channel();
+        let (body, stream) = body::stream(self.options.buffer_size, Box::new(move || {
+                let _ = sender.send(HttpMessage::BodyRead(token));
+            }));
+
+        self.body = Some(body);
+        self.handle(event_loop, handler, request.with_body_stream(stream));
+    }
+
+    fn is_body_paused(&self) -> bool {
+        self.body.as_ref().map(|body| body.is_full()).unwrap_or(false)
+    }
+
+    /// Counts the request against the connection and decides whether the connection
+    /// survives it, returning the `Connection` header to send back if any.
+    fn start_request(&mut self, request: &HttpRequest) -> Option<&'static str> {
+        self.requests += 1;
+
+        let limit_reached = match self.options.max_requests {
+            Some(max_requests) => self.requests >= max_requests,
+            None => false
+        };
+
+        let keep_alive = request.keep_alive() && !limit_reached && !self.draining;
+        if !keep_alive {
+            self.closing = true;
+        }
+
+        match (keep_alive, request.version()) {
+            (false, _) => Some("close"),
+            (true, HttpVersion::Http11) => None,
+            (true, _) => Some("keep-alive")
+        }
+    }
+
+    fn dispatch(&mut self, event_loop: &mut EventLoop<HttpHandler>, pending: PendingResponse) {
+        if !pending.promise.is_complete() {
+            let token = self.token.expect("No token assigned to connection");
+            let sender = event_loop.channel();
+            pending.promise.on_complete(move || {
+                    let _ = sender.send(HttpMessage::ResponseReady(token));
+                });
+        }
+
+        self.pipeline.push_back(pending);
+    }
+
+    fn write_ready_responses(&mut self, event_loop: &mut EventLoop<HttpHandler>) -> io::Result<()> {
+        loop {
+            // Responses behind a streamed body wait for it to end
+            if self.response_body.is_some() && !self.write_response_body() {
+                return Ok(());
+            }
+
+            let result = match self.pipeline.front() {
+                Some(pending) => pending.promise.take(),
+                None => return Ok(())
+            };
+
+            match result {
+                Some(result) => {
+                    let pending = self.pipeline.pop_front().unwrap();
+                    try!(self.respond(event_loop, pending, result));
+                },
+                None => return Ok(())
+            }
+        }
+    }
+
+    fn respond(&mut self, event_loop: &mut EventLoop<HttpHandler>, pending: PendingResponse, result: Result<HttpResponse, Box<Error + Send>>) -> io::Result<()> {
+        let mut response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                error!("Request handler failed {:?}", err.description());
+                HttpResponse::new(500)
+            }
+        };
+
+        // Interim responses go out as they are, the final response follows later
+        if response.status() < 200 {
+            self.queue(&response.to_bytes(pending.version));
+            return Ok(());
+        }
+
+        let close_requested = response.header("Connection")
+            .map(|connection| connection.to_lowercase().contains("close"))
+            .unwrap_or(false);
+
+        let stream = response.take_stream();
+        let chunked = pending.version == HttpVersion::Http11;
+        let sends_body = !pending.head && response.allows_body();
+
+        // Without chunked encoding the only way to end a streamed body is to close
+        if stream.is_some() && sends_body && !chunked {
+            self.closing = true;
+            self.pipeline.clear();
+        }
+
+        // The last response before closing tells the client not to reuse the connection
+        let connection_header = match self.closing && self.pipeline.is_empty() {
+            true => Some("close"),
+            false => pending.connection_header
+        };
+
+        if close_requested {
+            self.closing = true;
+            self.pipeline.clear();
+        } else if let Some(connection) = connection_header {
+            response = response.with_header("Connection", connection);
+        }
+
+        // Responses to HEAD carry the same headers as GET but never a body
+        match (stream, pending.head) {
+            (Some(receiver), _) => {
+                self.queue(&response.streaming_head_bytes(chunked));
+
+                // Dropping the receiver of a body that won't be sent stops its writer
+                if sends_body {
+                    let token = self.token.expect("No token assigned to connection");
+                    let sender = event_loop.channel();
+                    receiver.attach(self.options.buffer_size, Box::new(move || {
+                            let _ = sender.send(HttpMessage::ResponseReady(token));
+                        }));
+
+                    self.response_body = Some(StreamingResponse {
+                        receiver: receiver,
+                        chunked: chunked
+                    });
+                }
+            },
+            (None, true) => self.queue(&response.head_bytes(pending.version)),
+            (None, false) => self.queue(&response.to_bytes(pending.version))
+        }
+        Ok(())
+    }
+
+    /// Passes on what the handler has written to a streamed body, but only once the socket
+    /// has taken everything before it so a fast writer can't outrun a slow client. Returns
+    /// whether the body has ended.
+    fn write_response_body(&mut self) -> bool {
+        if self.buf.is_some() {
+            return false;
+        }
+
+        let (data, trailers, aborted, chunked) = match self.response_body {
+            Some(ref body) => {
+                let (data, trailers) = body.receiver.take();
+                let aborted = trailers.is_none() && body.receiver.is_aborted();
+                (data, trailers, aborted, body.chunked)
+            },
+            None => return true
+        };
+
+        let mut output = match chunked {
+            true => response::chunk_bytes(&data),
+            false => data
+        };
+
+        if let Some(ref trailers) = trailers {
+            if chunked {
+                output.extend(response::last_chunk_bytes(trailers));
+            }
+        }
+
+        if !output.is_empty() {
+            self.queue(&output);
+        }
+
+        // A body cut short is left unterminated so the client can tell it is incomplete
+        if aborted {
+            self.closing = true;
+            self.pipeline.clear();
+        }
+
+        let ended = trailers.is_some() || aborted;
+        if ended {
+            self.response_body = None;
+        }
+        ended
+    }
+
+    fn has_response_data(&self) -> bool {
+        self.response_body.as_ref().map(|body| body.receiver.is_ready()).unwrap_or(false)
+    }
+
+    /// Lets a request that is already being received finish, but parses nothing after it.
+    fn drain(&mut self) {
+        self.draining = true;
+
+        let output = match self.http2 {
+            Some(ref mut http2) => {
+                http2.go_away(ErrorCode::NoError);
+                http2.take_output()
+            },
+            None => Vec::new()
+        };
+
+        if !output.is_empty() {
+            self.queue(&output);
+        }
+
+        let started = match self.http_request {
+            Some(ref http_request) => http_request.is_started(),
+            None => false
+        };
+
+        if !started {
+            self.closing = true;
+        }
+    }
+
+    /// Whether the connection has flushed its last response and can be removed.
+    fn is_finished(&self) -> bool {
+        let no_more_requests = self.closing || (self.read_closed && self.unparsed.is_none());
+        no_more_requests && self.buf.is_none() && self.pipeline.is_empty() && self.response_body.is_none()
+    }
+
+    fn queue(&mut self, data: &[u8]) {
+        let queued = match self.buf.take() {
+            Some(pending) => {
+                let mut queued = ByteBuf::mut_with_capacity(pending.remaining() + data.len());
+                queued.write_slice(pending.bytes());
+                queued.write_slice(data);
+                queued.flip()
+            },
+            None => ByteBuf::from_slice(data)
+        };
+
+        self.buf = Some(queued);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut buf = match self.buf.take() {
+            Some(buf) => buf,
+            None => return Ok(())
+        };
+
+        match try!(self.sock.try_write_buf(&mut buf)) {
+            Some(_) if !buf.has_remaining() => (),
+            _ => self.buf = Some(buf)
+        }
+
+        Ok(())
+    }
+
+    fn reregister(&mut self, event_loop: &mut EventLoop<HttpHandler>) -> io::Result<()> {
+        // Output is drained before more requests are read, and reads pause while the
+        // pipeline is full and the read buffer is held in `unparsed`, or while a streamed
+        // body waits for the handler. Hangups are only noticed once reading resumes, so a
+        // paused body isn't cut short by a client that has already sent all of it. A streamed
+        // response body that `process` stopped writing carries on once the socket is writable.
+        let reading = (!self.closing || self.body.is_some()) && !self.read_closed && !self.is_body_paused();
+
+        self.interest = if self.buf.is_some() || self.has_response_data() {
+            EventSet::writable()
+        } else if reading && self.mut_buf.is_some() {
+            EventSet::readable() | EventSet::hup()
+        } else {
+            EventSet::none()
+        };
+
+        let token = self.token.expect("No token assigned to connection");
+        event_loop.reregister(&self.sock, token, self.interest, PollOpt::edge() | PollOpt::oneshot())
+    }
+}
+
+struct HttpServer {
+    sock: TcpListener,
+    conns: Slab<HttpConnection>,
+    options: ConnectionOptions,
+    shutdown_timeout_ms: u64,
+    shutting_down: bool,
+    handler: Box<Handler>
+}
+
+impl HttpServer {
+    fn accept(&mut self, event_loop: &mut EventLoop<HttpHandler>) -> io::Result<()> {
+        loop {
+            let sock = match try!(self.sock.accept()) {
+                Some(sock) => sock,
+                None => return Ok(())
+            };
+
+            let tok = match self.conns.insert(HttpConnection::new(sock, self.options)) {
+                Ok(tok) => tok,
+                Err(_) => {
+                    warn!("Connection limit reached, dropping connection");
+                    continue;
+                }
+            };
+
+            self.conns[tok].token = Some(tok);
+            let registered = event_loop.register_opt(&self.conns[tok].sock, tok, self.conns[tok].interest, PollOpt::edge() | PollOpt::oneshot());
+
+            match registered {
+                Ok(_) => self.conns[tok].schedule_timeout(event_loop),
+                Err(e) => {
+                    error!("Could not register connection {:?}", e);
+                    self.conns.remove(tok);
+                }
+            }
+        }
+    }
+
+    fn conn_ready(&mut self, event_loop: &mut EventLoop<HttpHandler>, tok: Token, events: EventSet) -> io::Result<()> {
+        {
+            let handler = &self.handler;
+            let conn = match self.conns.get_mut(tok) {
+                Some(conn) => conn,
+                None => return Ok(())
+            };
+
+            if events.is_error() {
+                return Err(conn.sock.take_socket_error().err()
+                    .unwrap_or(io::Error::new(io::ErrorKind::Other, "Socket error")));
+            }
+
+            // A hangup may arrive with data still unread, which is read until the end
+            // of the stream is reached one buffer per event like any other
+            if events.is_readable() || events.is_hup() {
+                try!(conn.readable(event_loop, &**handler));
+            }
+
+            if events.is_writable() {
+                try!(conn.writable(event_loop, &**handler));
+            }
+        }
+
+        self.update(event_loop, tok)
+    }
+
+    fn conn_response_ready(&mut self, event_loop: &mut EventLoop<HttpHandler>, tok: Token) -> io::Result<()> {
+        {
+            let handler = &self.handler;
+            match self.conns.get_mut(tok) {
+                Some(conn) => try!(conn.response_ready(event_loop, &**handler)),
+                None => return Ok(())
+            }
+        }
+
+        self.update(event_loop, tok)
+    }
+
+    fn conn_timeout(&mut self, event_loop: &mut EventLoop<HttpHandler>, tok: Token) -> io::Result<()> {
+        match self.conns.get_mut(tok) {
+            Some(conn) => try!(conn.timed_out(event_loop)),
+            None => return Ok(())
+        }
+
+        self.update(event_loop, tok)
+    }
+
+    /// Removes the connection once it has finished, otherwise re-arms its oneshot
+    /// registration and timer.
+    fn update(&mut self, event_loop: &mut EventLoop<HttpHandler>, tok: Token) -> io::Result<()> {
+        let finished = match self.conns.get(tok) {
+            Some(conn) => conn.is_finished(),
+            None => return Ok(())
+        };
+
+        if finished {
+            self.close(event_loop, tok);
+            return Ok(());
+        }
+
+        let conn = &mut self.conns[tok];
+        conn.schedule_timeout(event_loop);
+        conn.reregister(event_loop)
+    }
+
+    fn close(&mut self, event_loop: &mut EventLoop<HttpHandler>, tok: Token) {
+        if let Some(conn) = self.conns.remove(tok) {
+            if let Some((timeout, _, _)) = conn.timer {
+                event_loop.clear_timeout(timeout);
+            }
+
+            if let Err(e) = event_loop.deregister(&conn.sock) {
+                error!("Could not deregister connection {:?}", e);
+            }
+        }
+
+        if self.shutting_down && self.conns.is_empty() {
+            event_loop.shutdown();
+        }
+    }
+
+    /// Stops accepting, closes idle connections and gives the rest until the
+    /// shutdown deadline to finish their requests.
+    fn shutdown(&mut self, event_loop: &mut EventLoop<HttpHandler>) {
+        if self.shutting_down {
+            return;
+        }
+
+        self.shutting_down = true;
+        if let Err(e) = event_loop.deregister(&self.sock) {
+            error!("Could not deregister listener {:?}", e);
+        }
+
+        if self.conns.is_empty() {
+            event_loop.shutdown();
+            return;
+        }
+
+        if let Err(e) = event_loop.timeout_ms(SERVER, self.shutdown_timeout_ms) {
+            error!("Could not schedule shutdown deadline {:?}", e);
+        }
+
+        let tokens: Vec<Token> = self.conns.iter().filter_map(|conn| conn.token).collect();
+        for tok in tokens {
+            self.conns[tok].drain();
+
+            if let Err(e) = self.update(event_loop, tok) {
+                warn!("Closing connection {:?} after error {:?}", tok, e);
+                self.close(event_loop, tok);
+            }
+        }
+    }
+
+    fn shutdown_deadline(&mut self, event_loop: &mut EventLoop<HttpHandler>) {
+        let tokens: Vec<Token> = self.conns.iter().filter_map(|conn| conn.token).collect();
+        for tok in tokens {
+            self.close(event_loop, tok);
+        }
+
+        event_loop.shutdown();
+    }
+}
+
+struct HttpHandler {
+    server: HttpServer
+}
+
+impl HttpHandler {
+    fn new(srv: TcpListener, config: Server) -> HttpHandler {
+        HttpHandler {
+            server: HttpServer {
+                sock: srv,
+                conns: Slab::new_starting_at(Token(1), config.max_connections),
+                options: config.options,
+                shutdown_timeout_ms: config.shutdown_timeout_ms,
+                shutting_down: false,
+                handler: config.handler
+            }
+        }
+    }
+}
+
+impl mio::Handler for HttpHandler {
+
+    type Timeout = Token;
+    type Message = HttpMessage;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<HttpHandler>, token: Token, events:EventSet) {
+        if token == SERVER {
+            if let Err(e) = self.server.accept(event_loop) {
+                error!("Error accepting connection {:?}", e);
+            }
+
+            return;
+        }
+
+        if let Err(e) = self.server.conn_ready(event_loop, token, events) {
+            warn!("Closing connection {:?} after error {:?}", token, e);
+            self.server.close(event_loop, token);
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<HttpHandler>, token: Token) {
+        if token == SERVER {
+            return self.server.shutdown_deadline(event_loop);
+        }
+
+        if let Err(e) = self.server.conn_timeout(event_loop, token) {
+            warn!("Closing connection {:?} after error {:?}", token, e);
+            self.server.close(event_loop, token);
+        }
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<HttpHandler>, msg: HttpMessage) {
+        match msg {
+            HttpMessage::ResponseReady(token) => {
+                if let Err(e) = self.server.conn_response_ready(event_loop, token) {
+                    warn!("Closing connection {:?} after error {:?}", token, e);
+                    self.server.close(event_loop, token);
+                }
+            },
+            HttpMessage::BodyRead(token) => {
+                if let Err(e) = self.server.update(event_loop, token) {
+                    warn!("Closing connection {:?} after error {:?}", token, e);
+                    self.server.close(event_loop, token);
+                }
+            },
+            HttpMessage::Shutdown => self.server.shutdown(event_loop)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::io::{ErrorKind, Read, Write};
+    use std::mem;
+    use std::net::{Shutdown, SocketAddr, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use handler::Handler;
+    use headers::HeaderMap;
+    use promises::{self, Promise};
+    use request::{HttpRequest, ParserLimits, ParserMode};
+    use response::HttpResponse;
+    use super::{Server, ShutdownHandle};
+
+    /// Leaves every request unanswered until the test completes its promise.
+    #[derive(Clone)]
+    struct HeldHandler {
+        held: Arc<Mutex<Vec<(String, Promise<HttpResponse>)>>>
+    }
+
+    impl HeldHandler {
+        fn new() -> HeldHandler {
+            HeldHandler {
+                held: Arc::new(Mutex::new(Vec::new()))
+            }
+        }
+
+        fn len(&self) -> usize {
+            self.held.lock().unwrap().len()
+        }
+
+        fn wait_for(&self, count: usize) {
+            for _ in 0 .. 500 {
+                if self.len() >= count {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("Expected {} requests, got {}", count, self.len());
+        }
+
+        /// Answers the held requests from `start` on, last to first.
+        fn complete_reversed(&self, start: usize) {
+            let held = self.held.lock().unwrap();
+            for &(ref path, ref promise) in held[start ..].iter().rev() {
+                promise.complete(HttpResponse::new(200).with_body(path.as_bytes().to_vec()));
+            }
+        }
+    }
+
+    /// Reads streamed bodies on another thread and answers with the path and body length.
+    struct UploadHandler;
+
+    impl Handler for UploadHandler {
+        fn handle(&self, mut request: HttpRequest) -> Promise<HttpResponse> {
+            let promise = promises::incomplete();
+            let completer = promise.clone();
+            let path = String::from(request.path());
+            let buffered = request.body().len();
+            let stream = request.take_body_stream();
+
+            thread::spawn(move || {
+                let mut data = Vec::new();
+                let length = match stream {
+                    Some(mut stream) => stream.read_to_end(&mut data).map(|_| data.len()),
+                    None => Ok(buffered)
+                };
+
+                completer.complete(match length {
+                    Ok(length) => HttpResponse::new(200).with_body(format!("{}:{}", path, length).into_bytes()),
+                    Err(_) => HttpResponse::new(400)
+                });
+            });
+
+            promise
+        }
+
+        fn stream_body(&self, _: &HttpRequest) -> bool {
+            true
+        }
+    }
+
+    /// Streams every body but answers without reading it, except for requests to `/held`
+    /// which are left to `held`.
+    struct EarlyHandler {
+        held: HeldHandler
+    }
+
+    impl Handler for EarlyHandler {
+        fn handle(&self, request: HttpRequest) -> Promise<HttpResponse> {
+            match request.path() {
+                "/held" => self.held.handle(request),
+                path => promises::completed(Ok(HttpResponse::new(200).with_body(path.as_bytes().to_vec())))
+            }
+        }
+
+        fn stream_body(&self, _: &HttpRequest) -> bool {
+            true
+        }
+    }
+
+    impl Handler for HeldHandler {
+        fn handle(&self, request: HttpRequest) -> Promise<HttpResponse> {
+            let promise = promises::incomplete();
+            self.held.lock().unwrap().push((String::from(request.path()), promise.clone()));
+            promise
+        }
+    }
+
+    /// Runs a server on a free loopback port in its own thread, since a `Server` holding
+    /// its handler can't be sent to one after being built.
+    fn serve<F>(build: F) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<()>) where F: FnOnce() -> Server + Send + 'static {
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let listening = build().bind("127.0.0.1:0".parse().unwrap()).listen().unwrap();
+            tx.send((listening.local_addr().unwrap(), listening.shutdown_handle())).unwrap();
+            listening.run().unwrap();
+        });
+
+        let (addr, shutdown) = rx.recv().unwrap();
+        (addr, shutdown, thread)
+    }
+
+    // Short enough to keep the timeout tests quick, their assertions don't depend on it
+    const TIMEOUT_MS: u64 = 100;
+
+    fn echo_path(request: HttpRequest) -> HttpResponse {
+        HttpResponse::new(200).with_body(request.path().as_bytes().to_vec())
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5000))).unwrap();
+        stream
+    }
+
+    /// Reads one response delimited by its Content-Length.
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0];
+
+        while !head.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut byte) {
+                Ok(1) => head.push(byte[0]),
+                _ => panic!("Connection ended within headers {:?}", String::from_utf8_lossy(&head))
+            }
+        }
+
+        let head = String::from_utf8(head).unwrap();
+        let length = head.lines()
+            .filter_map(|line| match line.starts_with("Content-Length: ") {
+                true => line[16 ..].parse::<usize>().ok(),
+                false => None
+            })
+            .next()
+            .unwrap_or(0);
+
+        let mut body = Vec::with_capacity(length);
+        (&mut *stream).take(length as u64).read_to_end(&mut body).unwrap();
+        head + &String::from_utf8(body).unwrap()
+    }
+
+    /// Everything up to the server closing the connection, which an error also counts as.
+    /// Running into the read timeout doesn't, so a server that never closes fails the test.
+    fn read_to_close(stream: &mut TcpStream) -> String {
+        let mut data = Vec::new();
+        let mut chunk = [0; 4096];
+
+        loop {
+            match stream.read(&mut chunk) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                    panic!("Connection still open after {:?}", String::from_utf8_lossy(&data)),
+                Ok(0) | Err(_) => break,
+                Ok(length) => data.extend(chunk[.. length].iter().cloned())
+            }
+        }
+
+        String::from_utf8(data).unwrap()
+    }
+
+    /// A socket buffer size option such as `SO_SNDBUF`, as the kernel reports it.
+    fn buffer_size(stream: &TcpStream, option: libc::c_int) -> usize {
+        let mut size: libc::c_int = 0;
+        let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, option,
+                &mut size as *mut libc::c_int as *mut libc::c_void, &mut length)
+        };
+
+        assert_eq!(result, 0);
+        size as usize
+    }
+
+    fn stop(shutdown: ShutdownHandle, thread: thread::JoinHandle<()>) {
+        shutdown.shutdown();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_partial_writes() {
+        let (addr, shutdown, thread) = serve(|| Server::new(|request: HttpRequest| match request.path() {
+                "/large" => HttpResponse::new(200).with_body(vec![b'x'; 4 * 1024 * 1024]),
+                path => HttpResponse::new(200).with_body(path.as_bytes().to_vec())
+            }).buffer_size(256));
+
+        // The client holds off reading so the server fills the socket and waits for it to drain
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /large HTTP/1.1\r\n\r\nGET /after HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let large = read_response(&mut stream);
+        assert!(large.starts_with("HTTP/1.1 200 OK\r\nContent-Length: 4194304\r\n\r\nxxx"));
+        assert_eq!(large.len(), 4194304 + 44);
+
+        assert_eq!(read_to_close(&mut stream), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 6\r\n\r\n/after");
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_builder_options() {
+        let (addr, shutdown, thread) = serve(|| Server::new(echo_path)
+            .max_connections(1)
+            .buffer_size(64)
+            .parser_limits(ParserLimits::new().max_request_line(32))
+            .parser_mode(ParserMode::Strict));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/a");
+
+        // Only one connection is allowed, so a second is dropped on accept
+        let mut rejected = connect(addr);
+        let _ = rejected.write_all(b"GET /b HTTP/1.1\r\n\r\n");
+        assert_eq!(read_to_close(&mut rejected), "");
+
+        // Requests larger than the read buffer are still parsed
+        stream.write_all(b"GET /c HTTP/1.1\r\nX-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/c");
+
+        stream.write_all(b"GET /a/path/longer/than/the/limit HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_to_close(&mut stream).starts_with("HTTP/1.1 414 URI Too Long\r\nConnection: close\r\n"));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET / HTTP/1.1\nHost: a\r\n\r\n").unwrap();
+        assert!(read_to_close(&mut stream).starts_with("HTTP/1.1 400 Bad Request\r\nConnection: close\r\n"));
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_zero_copy_parsing() {
+        let (addr, shutdown, thread) = serve(|| Server::new(echo_path).zero_copy_parsing(true).parser_mode(ParserMode::Strict));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /b HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/a");
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/b");
+
+        // A head split across reads falls back to the state machine
+        stream.write_all(b"GET /c HTTP/1.1\r\nHo").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(b"st: a\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/c");
+
+        // Strict mode still applies
+        stream.write_all(b"GET /d HTTP/1.1\r\nHost: a\x01\r\n\r\n").unwrap();
+        assert!(read_to_close(&mut stream).starts_with("HTTP/1.1 400 Bad Request\r\nConnection: close\r\n"));
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_expect_without_body() {
+        let (addr, shutdown, thread) = serve(|| Server::new(echo_path));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /a HTTP/1.1\r\nExpect: 100-continue\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/a");
+
+        // HTTP/1.0 clients get no interim response
+        stream.write_all(b"GET /b HTTP/1.0\r\nExpect: 100-continue\r\n\r\n").unwrap();
+        assert_eq!(read_to_close(&mut stream), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n/b");
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_body_error_after_response() {
+        let held = HeldHandler::new();
+        let handler = EarlyHandler {held: held.clone()};
+        let (addr, shutdown, thread) = serve(move || Server::new(handler));
+
+        // The handler already answered, so the connection just closes
+        let mut stream = connect(addr);
+        stream.write_all(b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/a");
+        stream.write_all(b"zz\r\n").unwrap();
+        assert_eq!(read_to_close(&mut stream), "");
+
+        // An answer still queued behind an earlier response is sent as the last one
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /held HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n").unwrap();
+        held.wait_for(1);
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(b"zz\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        held.complete_reversed(0);
+        assert_eq!(read_to_close(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n/held\
+            HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n/b");
+
+        // Until the handler answers, the error takes the place of its response
+        let mut stream = connect(addr);
+        stream.write_all(b"POST /held HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n").unwrap();
+        held.wait_for(2);
+        stream.write_all(b"zz\r\n").unwrap();
+        assert!(read_to_close(&mut stream).starts_with("HTTP/1.1 400 Bad Request\r\nConnection: close\r\n"));
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_streamed_response_shares_event_loop() {
+        let finished = Arc::new(AtomicBool::new(false));
+        let writer_finished = finished.clone();
+        let (addr, shutdown, thread) = serve(move || Server::new(move |request: HttpRequest| {
+                if request.path() != "/stream" {
+                    return echo_path(request);
+                }
+
+                let (response, mut sink) = HttpResponse::new(200).streaming();
+                let finished = writer_finished.clone();
+                thread::spawn(move || {
+                    while !finished.load(Ordering::SeqCst) && sink.write_all(&[b'a'; 512]).is_ok() {}
+                    sink.finish(HeaderMap::new());
+                });
+                response
+            }).buffer_size(1024));
+
+        let mut streamed = connect(addr);
+        streamed.write_all(b"GET /stream HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let reader = thread::spawn(move || {
+            let mut tail = Vec::new();
+            let mut chunk = [0; 4096];
+            loop {
+                match streamed.read(&mut chunk) {
+                    Ok(0) | Err(_) => return tail,
+                    Ok(length) => {
+                        tail.extend(chunk[.. length].iter().cloned());
+                        let start = tail.len().saturating_sub(5);
+                        tail = tail[start ..].to_vec();
+                    }
+                }
+            }
+        });
+
+        // The body is written as fast as it is read, yet other connections are still served
+        thread::sleep(Duration::from_millis(50));
+        let mut other = connect(addr);
+        other.write_all(b"GET /b HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut other), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/b");
+
+        finished.store(true, Ordering::SeqCst);
+        assert_eq!(reader.join().unwrap(), b"0\r\n\r\n".to_vec());
+
+        stop(shutdown, thread);
+    }
+
+    /// Everything up to the server closing the connection, as raw bytes.
+    fn read_bytes_to_close(stream: &mut TcpStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        let _ = stream.read_to_end(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_server_http2_preface_only_first() {
+        let (addr, shutdown, thread) = serve(|| Server::new(echo_path));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /a HTTP/1.1\r\n\r\nPRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+        assert_eq!(read_to_close(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/a\
+            HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_http2_idle_timeout() {
+        let (addr, shutdown, thread) = serve(|| Server::new(echo_path).keep_alive_timeout_ms(100).http2_idle_timeout_ms(400));
+        let ping = b"\0\0\x08\x06\0\0\0\0\0pingpong";
+        let ping_ack = b"\0\0\x08\x06\x01\0\0\0\0pingpong";
+        let go_away = b"\0\0\x08\x07\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+        let mut stream = connect(addr);
+        stream.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0").unwrap();
+
+        // Activity keeps the connection open well past the keep-alive timeout
+        for _ in 0 .. 6 {
+            thread::sleep(Duration::from_millis(100));
+            stream.write_all(ping).unwrap();
+        }
+
+        let idle = Instant::now();
+        let data = read_bytes_to_close(&mut stream);
+        assert!(idle.elapsed() >= Duration::from_millis(250));
+        assert_eq!(data.windows(ping_ack.len()).filter(|window| *window == &ping_ack[..]).count(), 6);
+        assert!(data.ends_with(go_away));
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_max_requests_per_connection() {
+        let (addr, shutdown, thread) = serve(|| Server::new(echo_path).max_requests_per_connection(2));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /1 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/1");
+
+        stream.write_all(b"GET /2 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(read_to_close(&mut stream), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n/2");
+
+        // HTTP/1.0 clients are told when the connection is kept open
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /1 HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\n/1");
+
+        stream.write_all(b"GET /2 HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        assert_eq!(read_to_close(&mut stream), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n/2");
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_pipelined_responses_in_order() {
+        let handler = HeldHandler::new();
+        let server_handler = handler.clone();
+        let (addr, shutdown, thread) = serve(move || Server::new(server_handler));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\n\r\nGET /3 HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+        // The last request is answered first but still waits for the two ahead of it
+        handler.wait_for(3);
+        let completer = handler.clone();
+        thread::spawn(move || completer.complete_reversed(0));
+
+        assert_eq!(read_to_close(&mut stream), concat!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/1",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/2",
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n/3"));
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_max_pipelined_requests() {
+        let handler = HeldHandler::new();
+        let server_handler = handler.clone();
+        let (addr, shutdown, thread) = serve(move || Server::new(server_handler).max_pipelined_requests(2));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\n\r\nGET /3 HTTP/1.1\r\n\r\nGET /4 HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+        // Parsing stops while two requests await a response
+        handler.wait_for(2);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(handler.len(), 2);
+
+        handler.complete_reversed(0);
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/1");
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/2");
+
+        handler.wait_for(4);
+        handler.complete_reversed(2);
+        assert_eq!(read_to_close(&mut stream), concat!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/3",
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n/4"));
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_reads_to_end_after_half_close() {
+        let (addr, shutdown, thread) = serve(|| Server::new(UploadHandler).buffer_size(64));
+
+        // Everything sent before shutting down the write side is several buffers' worth
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\n\r\nGET /3 HTTP/1.1\r\n\r\n").unwrap();
+        stream.write_all(b"POST /4 HTTP/1.1\r\nContent-Length: 1000\r\n\r\n").unwrap();
+        stream.write_all(&[b'x'; 1000]).unwrap();
+        stream.write_all(b"GET /5 HTTP/1.1\r\n\r\n").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        assert_eq!(read_to_close(&mut stream), concat!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n/1:0",
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n/2:0",
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n/3:0",
+            "HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\n/4:1000",
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n/5:0"));
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_header_timeout() {
+        let (addr, shutdown, thread) = serve(|| Server::new(echo_path).header_timeout_ms(TIMEOUT_MS));
+
+        // A slowloris client trickling in headers doesn't hold on to the connection
+        let mut stream = connect(addr);
+        let mut trickle = stream.try_clone().unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::spawn(move || {
+            for _ in 0 .. 1000 {
+                if trickle.write_all(b"X-Slow: a\r\n").is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(TIMEOUT_MS / 10));
+            }
+        });
+
+        assert_eq!(read_to_close(&mut stream), "HTTP/1.1 408 Request Timeout\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_keep_alive_timeout() {
+        let (addr, shutdown, thread) = serve(|| Server::new(echo_path).keep_alive_timeout_ms(TIMEOUT_MS));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET /1 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/1");
+
+        // An idle connection is closed without a response, but not before the timeout
+        let started = Instant::now();
+        assert_eq!(read_to_close(&mut stream), "");
+        assert!(started.elapsed() >= Duration::from_millis(TIMEOUT_MS / 2));
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_body_timeout() {
+        let (addr, shutdown, thread) = serve(|| Server::new(echo_path).body_timeout_ms(TIMEOUT_MS));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+        assert_eq!(read_to_close(&mut stream), "");
+
+        stop(shutdown, thread);
+    }
+
+    #[test]
+    fn test_server_write_timeout() {
+        let (addr, shutdown, thread) = serve(|| Server::new(|request: HttpRequest| {
+                let length = request.path()[1 ..].parse().unwrap();
+                HttpResponse::new(200).with_body(vec![b'x'; length])
+            }).write_timeout_ms(TIMEOUT_MS));
+
+        // A client that stops reading is dropped with the response unfinished. The response
+        // has to outgrow the socket buffers first, which loopback sizes the same at both ends,
+        // and a busy machine gets more time and a larger response on each attempt
+        for attempt in 0 .. 4 {
+            let mut stream = connect(addr);
+            let length = (buffer_size(&stream, libc::SO_SNDBUF) + buffer_size(&stream, libc::SO_RCVBUF)) * (2 << attempt);
+            stream.write_all(format!("GET /{} HTTP/1.1\r\n\r\n", length).as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(TIMEOUT_MS * (4 << attempt)));
+
+            let received = read_to_close(&mut stream);
+            assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+            if received.len() < length {
+                return stop(shutdown, thread);
+            }
+        }
+
+        panic!("Every response was written in full");
+    }
+
+    #[test]
+    fn test_server_shutdown_drains_requests() {
+        let handler = HeldHandler::new();
+        let server_handler = handler.clone();
+        let (addr, shutdown, thread) = serve(move || Server::new(server_handler));
+
+        let mut idle = connect(addr);
+        idle.write_all(b"GET /idle HTTP/1.1\r\n\r\n").unwrap();
+        handler.wait_for(1);
+        handler.complete_reversed(0);
+        assert_eq!(read_response(&mut idle), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n/idle");
+
+        let mut busy = connect(addr);
+        busy.write_all(b"GET /busy HTTP/1.1\r\n\r\n").unwrap();
+        handler.wait_for(2);
+
+        // Idle connections close straight away, while the request in flight is still answered
+        assert!(shutdown.shutdown());
+        assert_eq!(read_to_close(&mut idle), "");
+
+        // No new connections are accepted
+        let mut late = connect(addr);
+        let _ = late.write_all(b"GET /late HTTP/1.1\r\n\r\n");
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(handler.len(), 2);
+
+        handler.complete_reversed(1);
+        assert_eq!(read_to_close(&mut busy), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\n/busy");
+
+        // The event loop stops once the last connection has finished
+        thread.join().unwrap();
+        assert_eq!(read_to_close(&mut late), "");
+        assert!(!shutdown.shutdown());
+    }
+
+    #[test]
+    fn test_server_shutdown_deadline() {
+        let handler = HeldHandler::new();
+        let server_handler = handler.clone();
+        let (addr, shutdown, thread) = serve(move || Server::new(server_handler).shutdown_timeout_ms(200));
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        handler.wait_for(1);
+
+        // A request that never completes is dropped at the deadline
+        let started = Instant::now();
+        shutdown.shutdown();
+        assert_eq!(read_to_close(&mut stream), "");
+        thread.join().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(started.elapsed() < Duration::from_millis(1500));
+    }
+}
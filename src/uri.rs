@@ -0,0 +1,315 @@
+use std::ascii::AsciiExt;
+use std::fmt;
+use request::{HttpError, HttpMethod};
+
+/// The four shapes a request target can take, RFC 9112 section 3.2.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TargetForm {
+    /// `/path?query`, used for most requests
+    Origin,
+    /// `http://host/path?query`, used when talking to a proxy
+    Absolute,
+    /// `host:port`, only for CONNECT
+    Authority,
+    /// `*`, only for server-wide OPTIONS
+    Asterisk
+}
+
+/// A parsed request target. Percent-encodings are validated up front so the decoded
+/// path, segments and query pairs are always available.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Uri {
+    form: TargetForm,
+    scheme: Option<String>,
+    authority: Option<String>,
+    path: String,
+    query: Option<String>,
+    decoded_path: String,
+    segments: Vec<String>,
+    query_pairs: Vec<(String, String)>
+}
+
+impl Uri {
+    pub fn parse(target: &str, method: &HttpMethod) -> Result<Uri, HttpError> {
+        if target.is_empty() || target.contains('#') {
+            return Err(HttpError::InvalidTarget(String::from(target)));
+        }
+
+        if *method == HttpMethod::CONNECT {
+            return Uri::parse_authority(target);
+        }
+
+        if target == "*" {
+            return match *method {
+                HttpMethod::OPTIONS | HttpMethod::HTTP2 => Uri::new(TargetForm::Asterisk, None, None, target, None),
+                _ => Err(HttpError::InvalidTarget(String::from(target)))
+            };
+        }
+
+        if target.starts_with('/') {
+            let (path, query) = split_query(target);
+            return Uri::new(TargetForm::Origin, None, None, path, query);
+        }
+
+        match target.find("://") {
+            Some(index) if index > 0 && is_scheme(&target[.. index]) => {
+                let rest = &target[index + 3 ..];
+                let end = rest.find(|character| character == '/' || character == '?').unwrap_or(rest.len());
+                let (authority, rest) = rest.split_at(end);
+
+                if authority.is_empty() {
+                    return Err(HttpError::InvalidTarget(String::from(target)));
+                }
+
+                let (path, query) = split_query(rest);
+                let path = if path.is_empty() { "/" } else { path };
+
+                Uri::new(TargetForm::Absolute, Some(&target[.. index]), Some(authority), path, query)
+            },
+            _ => Err(HttpError::InvalidTarget(String::from(target)))
+        }
+    }
+
+    fn parse_authority(target: &str) -> Result<Uri, HttpError> {
+        let valid = match target.rfind(':') {
+            Some(index) => {
+                let port = &target[index + 1 ..];
+                index > 0 && !port.is_empty() && port.bytes().all(|byte| byte >= b'0' && byte <= b'9')
+                    && !target.contains(|character| character == '/' || character == '?' || character == '@')
+            },
+            None => false
+        };
+
+        match valid {
+            true => Uri::new(TargetForm::Authority, None, Some(target), "", None),
+            false => Err(HttpError::InvalidTarget(String::from(target)))
+        }
+    }
+
+    fn new(form: TargetForm, scheme: Option<&str>, authority: Option<&str>, path: &str, query: Option<&str>) -> Result<Uri, HttpError> {
+        let decoded_path = try!(decode_string(path, false));
+
+        let mut segments = Vec::new();
+        if path.starts_with('/') {
+            for segment in path[1 ..].split('/') {
+                segments.push(try!(decode_string(segment, false)));
+            }
+        }
+
+        let mut query_pairs = Vec::new();
+        for pair in query.unwrap_or("").split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(index) => (&pair[.. index], &pair[index + 1 ..]),
+                None => (pair, "")
+            };
+
+            query_pairs.push((try!(decode_string(key, true)), try!(decode_string(value, true))));
+        }
+
+        Ok(Uri {
+            form: form,
+            scheme: scheme.map(String::from),
+            authority: authority.map(String::from),
+            path: String::from(path),
+            query: query.map(String::from),
+            decoded_path: decoded_path,
+            segments: segments,
+            query_pairs: query_pairs
+        })
+    }
+
+    pub fn form(&self) -> TargetForm {
+        self.form
+    }
+
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_ref().map(|scheme| scheme.as_ref())
+    }
+
+    pub fn authority(&self) -> Option<&str> {
+        self.authority.as_ref().map(|authority| authority.as_ref())
+    }
+
+    /// The path exactly as sent, still percent-encoded.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn decoded_path(&self) -> &str {
+        &self.decoded_path
+    }
+
+    /// The decoded segments between slashes, so an encoded `%2F` stays inside its segment.
+    pub fn segments(&self) -> &[String] {
+        &self.segments
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_ref().map(|query| query.as_ref())
+    }
+
+    /// Query parameters in the order they were sent, decoded as `application/x-www-form-urlencoded`.
+    pub fn query_pairs(&self) -> &[(String, String)] {
+        &self.query_pairs
+    }
+
+    /// The first value of the query parameter `name`.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query_pairs.iter()
+            .find(|&&(ref key, _)| key == name)
+            .map(|&(_, ref value)| value.as_ref())
+    }
+}
+
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref scheme) = self.scheme {
+            try!(write!(f, "{}://", scheme));
+        }
+
+        match self.form {
+            TargetForm::Asterisk => try!(f.write_str("*")),
+            _ => try!(write!(f, "{}{}", self.authority().unwrap_or(""), self.path))
+        }
+
+        match self.query {
+            Some(ref query) => write!(f, "?{}", query),
+            None => Ok(())
+        }
+    }
+}
+
+fn split_query(target: &str) -> (&str, Option<&str>) {
+    match target.find('?') {
+        Some(index) => (&target[.. index], Some(&target[index + 1 ..])),
+        None => (target, None)
+    }
+}
+
+fn is_scheme(value: &str) -> bool {
+    let mut bytes = value.bytes();
+    match bytes.next() {
+        Some(first) if first.is_ascii_alphabetic() => (),
+        _ => return false
+    }
+
+    bytes.all(|byte| byte.is_ascii_alphanumeric() || byte == b'+' || byte == b'-' || byte == b'.')
+}
+
+fn decode_string(value: &str, plus_as_space: bool) -> Result<String, HttpError> {
+    let decoded = try!(percent_decode(value, plus_as_space));
+    String::from_utf8(decoded).map_err(HttpError::from)
+}
+
+fn percent_decode(value: &str, plus_as_space: bool) -> Result<Vec<u8>, HttpError> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let high = bytes.get(index + 1).and_then(|&byte| (byte as char).to_digit(16));
+                let low = bytes.get(index + 2).and_then(|&byte| (byte as char).to_digit(16));
+
+                match (high, low) {
+                    (Some(high), Some(low)) => decoded.push((high * 16 + low) as u8),
+                    _ => return Err(HttpError::InvalidPercentEncoding(String::from(value)))
+                }
+
+                index += 3;
+            },
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                index += 1;
+            },
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use request::{HttpError, HttpMethod};
+    use super::{TargetForm, Uri};
+
+    #[test]
+    fn test_uri_origin_form() {
+        let uri = Uri::parse("/files/my%20docs/a%2Fb?q=rust+http&lang=en&empty&x=%26", &HttpMethod::GET).unwrap();
+
+        assert_eq!(uri.form(), TargetForm::Origin);
+        assert_eq!(uri.path(), "/files/my%20docs/a%2Fb");
+        assert_eq!(uri.decoded_path(), "/files/my docs/a/b");
+        assert_eq!(uri.segments(), &["files", "my docs", "a/b"]);
+        assert_eq!(uri.query(), Some("q=rust+http&lang=en&empty&x=%26"));
+        assert_eq!(uri.query_pairs(), &[
+            (String::from("q"), String::from("rust http")),
+            (String::from("lang"), String::from("en")),
+            (String::from("empty"), String::new()),
+            (String::from("x"), String::from("&"))
+        ]);
+        assert_eq!(uri.query_param("lang"), Some("en"));
+    }
+
+    #[test]
+    fn test_uri_absolute_form() {
+        let uri = Uri::parse("http://example.com:8080/index.html?a=1", &HttpMethod::GET).unwrap();
+
+        assert_eq!(uri.form(), TargetForm::Absolute);
+        assert_eq!(uri.scheme(), Some("http"));
+        assert_eq!(uri.authority(), Some("example.com:8080"));
+        assert_eq!(uri.path(), "/index.html");
+        assert_eq!(uri.query_param("a"), Some("1"));
+
+        let uri = Uri::parse("https://example.com", &HttpMethod::GET).unwrap();
+        assert_eq!(uri.path(), "/");
+        assert_eq!(uri.to_string(), "https://example.com/");
+    }
+
+    #[test]
+    fn test_uri_authority_and_asterisk_forms() {
+        let uri = Uri::parse("example.com:443", &HttpMethod::CONNECT).unwrap();
+        assert_eq!(uri.form(), TargetForm::Authority);
+        assert_eq!(uri.authority(), Some("example.com:443"));
+
+        let uri = Uri::parse("*", &HttpMethod::OPTIONS).unwrap();
+        assert_eq!(uri.form(), TargetForm::Asterisk);
+        assert_eq!(uri.to_string(), "*");
+    }
+
+    #[test]
+    fn test_uri_invalid_targets() {
+        let targets = vec![
+            ("*", HttpMethod::GET),
+            ("/", HttpMethod::CONNECT),
+            ("example.com", HttpMethod::CONNECT),
+            ("index.html", HttpMethod::GET),
+            ("/page#fragment", HttpMethod::GET),
+            ("http:///path", HttpMethod::GET),
+            // Neither is a scheme, though every byte of the second is alphabetic read as Latin-1
+            ("\u{e9}://host/", HttpMethod::GET),
+            ("\u{ea}://host/", HttpMethod::GET)
+        ];
+
+        for (target, method) in targets {
+            assert_eq!(Uri::parse(target, &method), Err(HttpError::InvalidTarget(String::from(target))));
+        }
+    }
+
+    #[test]
+    fn test_uri_invalid_percent_encoding() {
+        for target in vec!["/a%2", "/a%zz", "/?key=%G1"] {
+            match Uri::parse(target, &HttpMethod::GET) {
+                Err(HttpError::InvalidPercentEncoding(_)) => (),
+                _ => panic!("Expected InvalidPercentEncoding for {}", target)
+            }
+        }
+
+        assert_eq!(Uri::parse("/%ff", &HttpMethod::GET), Err(HttpError::InvalidUtf8));
+    }
+}